@@ -0,0 +1,23 @@
+-- Analysis that is computed in rust straight from the intents in the game record,
+-- so it exists for every finished game without running the simulator.
+
+CREATE TABLE IF NOT EXISTS analysis_1.player_intents (
+    game_id CHAR(8) NOT NULL,
+    client_id CHAR(8) NOT NULL,
+    username TEXT,
+    total_intents INTEGER NOT NULL,
+    -- Index is the minute of the game
+    intents_per_minute INTEGER[] NOT NULL,
+    attacks INTEGER NOT NULL,
+    attack_troops_sent BIGINT NOT NULL,
+    boats INTEGER NOT NULL,
+    boat_troops_sent BIGINT NOT NULL,
+    -- unit type -> count
+    builds JSONB NOT NULL DEFAULT '{}',
+    upgrades JSONB NOT NULL DEFAULT '{}',
+    -- [{ turn, ratio }]
+    troop_ratio_changes JSONB NOT NULL DEFAULT '[]',
+    inserted_at_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW()),
+    PRIMARY KEY (game_id, client_id),
+    FOREIGN KEY (game_id) REFERENCES public.finished_games(game_id) ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+-- Every poll of a public lobby, so we can see how it filled up over time
+
+CREATE TABLE IF NOT EXISTS lobby_snapshots (
+    game_id CHAR(8) NOT NULL,
+    seen_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW()),
+    num_clients INTEGER NOT NULL,
+    ms_until_start BIGINT NOT NULL,
+    PRIMARY KEY (game_id, seen_unix_sec),
+    FOREIGN KEY (game_id) REFERENCES public.lobbies(game_id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+-- What a registered user is allowed to do beyond normal use of the site.
+-- Granted by hand for now, e.g.
+--   INSERT INTO social.user_permissions (user_id, permission) VALUES ('abcdefghij', 'import_games');
+
+CREATE TABLE IF NOT EXISTS social.user_permissions (
+    user_id CHAR(10) NOT NULL,
+    -- See `Permission` in oauth.rs. 'admin' grants everything.
+    permission TEXT NOT NULL,
+    granted_at_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW()),
+    PRIMARY KEY (user_id, permission),
+    FOREIGN KEY (user_id) REFERENCES social.registered_users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,21 @@
+-- Games we could not download yet, so we can back off instead of asking for them on every run
+
+CREATE TYPE game_fetch_outcome AS ENUM (
+    'NotFound',
+    'FetchError'
+);
+
+CREATE TABLE IF NOT EXISTS public.game_fetch_ledger (
+    game_id CHAR(8) NOT NULL PRIMARY KEY,
+    attempts INTEGER NOT NULL,
+    last_outcome game_fetch_outcome NOT NULL,
+    last_error TEXT,
+    last_attempt_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW()),
+    next_check_unix_sec BIGINT NOT NULL,
+    -- We ran out of attempts and will not ask for this game again
+    abandoned BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (game_id) REFERENCES public.lobbies(game_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS game_fetch_ledger_next_check_idx
+    ON public.game_fetch_ledger (next_check_unix_sec) WHERE NOT abandoned;
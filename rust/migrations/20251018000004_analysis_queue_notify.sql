@@ -0,0 +1,22 @@
+-- Tell anyone listening on the analysis_queue channel when a game is queued or its status changes,
+-- so the server and workers don't have to poll. The payload is {"game_id": ..., "status": ...}.
+
+CREATE OR REPLACE FUNCTION public.notify_analysis_queue() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'analysis_queue',
+        json_build_object('game_id', NEW.game_id, 'status', NEW.status)::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER analysis_queue_notify_insert
+    AFTER INSERT ON public.analysis_queue
+    FOR EACH ROW EXECUTE FUNCTION public.notify_analysis_queue();
+
+CREATE TRIGGER analysis_queue_notify_update
+    AFTER UPDATE OF status ON public.analysis_queue
+    FOR EACH ROW
+    WHEN (OLD.status IS DISTINCT FROM NEW.status)
+    EXECUTE FUNCTION public.notify_analysis_queue();
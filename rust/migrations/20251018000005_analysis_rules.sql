@@ -0,0 +1,30 @@
+-- Rules for which finished games get analyzed automatically, replacing the auto_analyze_games
+-- config row. Rules are checked in rule_order, and the first enabled rule that matches decides.
+
+CREATE TABLE IF NOT EXISTS public.analysis_rules (
+    rule_id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    rule_order INTEGER NOT NULL DEFAULT 0,
+    -- Every condition that is not null must hold for the rule to match
+    game_map TEXT,
+    game_mode TEXT,
+    -- Same encoding as lobbies.teams: 0 for FFA, n for n teams, -n for parties of n
+    teams INTEGER,
+    min_players INTEGER,
+    max_players INTEGER,
+    min_duration_sec BIGINT,
+    max_duration_sec BIGINT,
+    requires_tracked_player BOOLEAN NOT NULL DEFAULT FALSE,
+    -- A matching game is queued at this priority, or skipped if enqueue is false
+    enqueue BOOLEAN NOT NULL DEFAULT TRUE,
+    priority INTEGER NOT NULL DEFAULT 0,
+    created_at_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())
+);
+
+-- auto_analyze_games = 'true' meant every finished game, so keep doing that
+INSERT INTO public.analysis_rules (name, rule_order)
+SELECT 'Every game', 1000
+WHERE EXISTS (SELECT 1 FROM config WHERE key = 'auto_analyze_games' AND value = 'true');
+
+DELETE FROM config WHERE key = 'auto_analyze_games';
@@ -0,0 +1,17 @@
+-- Tell servers to reload their runtime settings when the config table changes.
+-- The payload is the key that changed.
+
+CREATE OR REPLACE FUNCTION public.notify_config() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('config', OLD.key);
+    ELSE
+        PERFORM pg_notify('config', NEW.key);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER config_notify
+    AFTER INSERT OR UPDATE OR DELETE ON public.config
+    FOR EACH ROW EXECUTE FUNCTION public.notify_config();
@@ -0,0 +1,17 @@
+-- Analysis workers claim jobs through the API instead of connecting to the database. Each worker
+-- has its own token, and holds a lease on the job it is running that it must keep renewing.
+
+CREATE TABLE IF NOT EXISTS public.analysis_workers (
+    worker_id TEXT NOT NULL PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW()),
+    last_seen_unix_sec BIGINT
+);
+
+ALTER TABLE public.analysis_queue
+    ADD COLUMN IF NOT EXISTS worker_id TEXT,
+    -- A Running job whose lease has expired goes back to Pending
+    ADD COLUMN IF NOT EXISTS lease_expires_unix_sec BIGINT;
+
+CREATE INDEX IF NOT EXISTS analysis_queue_lease_idx
+    ON public.analysis_queue (lease_expires_unix_sec) WHERE status = 'Running';
@@ -0,0 +1,44 @@
+-- The order Pending analysis jobs are served in. Higher priority goes first. Within a priority,
+-- users take turns: everyone's oldest job comes before anyone's second, and among those the user
+-- who was served longest ago goes first. Games queued without a user count as one user.
+
+ALTER TABLE public.analysis_queue ADD COLUMN IF NOT EXISTS priority INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS analysis_queue_user_started_idx
+    ON public.analysis_queue (requesting_user_id, started_unix_sec);
+
+CREATE OR REPLACE VIEW public.analysis_queue_order AS
+WITH pending AS (
+    SELECT
+        aq.game_id,
+        aq.requesting_user_id,
+        aq.priority,
+        aq.requested_unix_sec,
+        ROW_NUMBER() OVER (
+            PARTITION BY aq.priority, aq.requesting_user_id
+            ORDER BY aq.requested_unix_sec, aq.game_id
+        ) AS user_turn
+    FROM public.analysis_queue aq
+    WHERE aq.status = 'Pending'
+),
+last_served AS (
+    SELECT requesting_user_id, MAX(started_unix_sec) AS last_started_unix_sec
+    FROM public.analysis_queue
+    WHERE started_unix_sec IS NOT NULL
+    GROUP BY requesting_user_id
+)
+SELECT
+    p.game_id,
+    p.requesting_user_id,
+    p.priority,
+    ROW_NUMBER() OVER (
+        ORDER BY
+            p.priority DESC,
+            p.user_turn,
+            ls.last_started_unix_sec ASC NULLS FIRST,
+            p.requested_unix_sec,
+            p.game_id
+    ) AS position
+FROM pending p
+    LEFT JOIN last_served ls
+    ON ls.requesting_user_id IS NOT DISTINCT FROM p.requesting_user_id;
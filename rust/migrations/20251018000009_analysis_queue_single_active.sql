@@ -0,0 +1,21 @@
+-- A game can only have one Pending or Running analysis job. Older rows for a game stay as its
+-- history. Duplicates from before this are cancelled, keeping a Running job over a Pending one,
+-- and the oldest otherwise.
+
+WITH ranked AS (
+    SELECT
+        ctid,
+        ROW_NUMBER() OVER (
+            PARTITION BY game_id
+            ORDER BY (status = 'Running') DESC, requested_unix_sec ASC
+        ) AS n
+    FROM public.analysis_queue
+    WHERE status IN ('Pending', 'Running')
+)
+UPDATE public.analysis_queue aq
+SET status = 'Cancelled'
+FROM ranked
+WHERE aq.ctid = ranked.ctid AND ranked.n > 1;
+
+CREATE UNIQUE INDEX IF NOT EXISTS analysis_queue_one_active_idx
+    ON public.analysis_queue (game_id) WHERE status IN ('Pending', 'Running');
@@ -0,0 +1,71 @@
+-- Every time a job runs is kept as an attempt, so we can tell why a game ended up Failed or
+-- Stalled. Triggers open an attempt when a job becomes Running and close it when it stops, so
+-- workers that update the queue directly are recorded too. Workers going through the API also
+-- send an error message.
+
+CREATE TABLE IF NOT EXISTS public.analysis_attempts (
+    attempt_id BIGSERIAL PRIMARY KEY,
+    game_id CHAR(8) NOT NULL,
+    started_unix_sec BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW()),
+    finished_unix_sec BIGINT,
+    worker_id TEXT,
+    analysis_engine_version TEXT,
+    -- Null while the attempt is running
+    outcome analysis_queue_status,
+    -- The error message, or the whole stack
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS analysis_attempts_game_idx
+    ON public.analysis_attempts (game_id, attempt_id);
+
+CREATE OR REPLACE FUNCTION public.record_analysis_attempt() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.status = 'Running' THEN
+        UPDATE public.analysis_attempts
+        SET
+            finished_unix_sec = EXTRACT(EPOCH FROM NOW()),
+            -- Going back to Pending means the worker's lease expired and the job was requeued
+            outcome = CASE WHEN NEW.status = 'Pending' THEN 'Stalled' ELSE NEW.status END,
+            error = CASE WHEN NEW.status = 'Pending' THEN 'Worker lease expired' ELSE NULL END
+        WHERE game_id = NEW.game_id AND finished_unix_sec IS NULL;
+    END IF;
+
+    IF NEW.status = 'Running' THEN
+        INSERT INTO public.analysis_attempts (game_id, started_unix_sec, worker_id)
+        VALUES (
+            NEW.game_id,
+            COALESCE(NEW.started_unix_sec, EXTRACT(EPOCH FROM NOW())),
+            NEW.worker_id
+        );
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER analysis_queue_attempt_insert
+    AFTER INSERT ON public.analysis_queue
+    FOR EACH ROW
+    WHEN (NEW.status = 'Running')
+    EXECUTE FUNCTION public.record_analysis_attempt();
+
+CREATE TRIGGER analysis_queue_attempt_update
+    AFTER UPDATE OF status ON public.analysis_queue
+    FOR EACH ROW
+    WHEN (OLD.status IS DISTINCT FROM NEW.status)
+    EXECUTE FUNCTION public.record_analysis_attempt();
+
+-- The engine version is known once the analysis is saved, before the job is marked Completed
+CREATE OR REPLACE FUNCTION public.record_analysis_engine_version() RETURNS trigger AS $$
+BEGIN
+    UPDATE public.analysis_attempts
+    SET analysis_engine_version = NEW.analysis_engine_version
+    WHERE game_id = NEW.game_id AND finished_unix_sec IS NULL;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER completed_analysis_attempt_version
+    AFTER INSERT OR UPDATE OF analysis_engine_version ON analysis_1.completed_analysis
+    FOR EACH ROW EXECUTE FUNCTION public.record_analysis_engine_version();
@@ -70,11 +70,54 @@ async fn construction_events_handler(
     Ok(Json(res))
 }
 
+async fn intents_handler(
+    Extension(db): Extension<PgPool>,
+    Path(game_id): Path<String>,
+) -> axum::response::Result<Json<super::intents::ResIntentsOverGame>> {
+    let res = super::intents::get_intents_over_game(db, &game_id)
+        .await
+        .map_err(|e| error_response(500, &format!("Failed to get intents: {}", e)))?
+        .ok_or_else(|| {
+            (
+                axum::http::StatusCode::NOT_FOUND,
+                error_response(404, &format!("No intent analysis for game {}", game_id)),
+            )
+        })?;
+
+    Ok(Json(res))
+}
+
+/// The whole analysis as NDJSON, in the format workers upload it in
+async fn export_handler(
+    Extension(db): Extension<PgPool>,
+    Path(game_id): Path<String>,
+) -> axum::response::Result<axum::response::Response> {
+    let res = super::ingest::export_analysis(&db, &game_id)
+        .await
+        .map_err(|e| error_response(500, &format!("Failed to export analysis: {}", e)))?
+        .ok_or_else(|| {
+            (
+                axum::http::StatusCode::NOT_FOUND,
+                error_response(404, &format!("Game {} has not been analyzed", game_id)),
+            )
+        })?;
+
+    Ok(axum::response::Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(axum::body::Body::from(res))
+        .expect("Failed to build response for analysis export"))
+}
+
 pub fn analysis_api_router() -> ApiRouter {
     ApiRouter::new()
         .route("/{game_id}/get_player_stats", get(player_stats_handler))
         .route("/{game_id}/get_general_events", get(general_events_handler))
         .route("/{game_id}/get_display_events", get(display_events_handler))
-        .route("/{game_id}/get_construction_events", get(construction_events_handler))
+        .route(
+            "/{game_id}/get_construction_events",
+            get(construction_events_handler),
+        )
         .route("/{game_id}/players", get(players_handler))
+        .route("/{game_id}/intents", get(intents_handler))
+        .route("/{game_id}/export", get(export_handler))
 }
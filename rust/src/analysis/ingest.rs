@@ -0,0 +1,864 @@
+//! Saving a whole analysis of a game in one go, as uploaded by an analysis worker.
+//!
+//! The payload is NDJSON, optionally gzipped, with one row per line. Each row says which table it
+//! belongs to in `table`, and exactly one row is the `header`:
+//!
+//! ```text
+//! {"table":"header","analysis_engine_version":"v1"}
+//! {"table":"player","id":"Abc12345","client_id":"Xyz98765","small_id":1,"player_type":"HUMAN","name":"bob"}
+//! {"table":"player_update","small_id":1,"tick":10,"player_alive":true,"player_connected":true,"tiles_owned":-20000,"gold":-25000,"workers":-31000,"troops":-21000}
+//! ```
+//!
+//! The rows are checked against the players of the game, then any previous analysis is replaced
+//! in a single transaction using `COPY`, so a game never ends up with half an analysis.
+//!
+//! [`export_analysis`] writes a saved analysis back out in the same format, which is how a mirror
+//! copies analyses from another instance.
+
+use std::collections::HashSet;
+use std::io::Read;
+
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::game_record::GameRecord;
+
+/// Largest analysis we accept in an upload, after decompressing
+pub const MAX_PAYLOAD_BYTES: usize = 256 * 1024 * 1024;
+
+/// One line of the payload. The fields match the columns of the `analysis_1` tables.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "table", rename_all = "snake_case")]
+pub enum AnalysisRow {
+    Header {
+        analysis_engine_version: String,
+    },
+    Player {
+        id: String,
+        client_id: Option<String>,
+        small_id: i16,
+        player_type: String,
+        name: String,
+        flag: Option<String>,
+        team: Option<i16>,
+    },
+    /// The numbers are compressed, see [`super::decompress_value_from_db`]
+    PlayerUpdate {
+        small_id: i16,
+        tick: i16,
+        player_alive: bool,
+        player_connected: bool,
+        tiles_owned: i16,
+        gold: i16,
+        workers: i16,
+        troops: i16,
+    },
+    GeneralEvent {
+        tick: i16,
+        event_type: String,
+        data: serde_json::Value,
+    },
+    DisplayEvent {
+        tick: i16,
+        message_type: String,
+        message: String,
+        player_id: i16,
+        gold_amount: Option<i32>,
+    },
+    SpawnLocation {
+        client_id: String,
+        tick: i16,
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        previous_spawns: Option<serde_json::Value>,
+    },
+    ConstructionEvent {
+        client_id: String,
+        small_id: i16,
+        tick: i16,
+        unit_type: String,
+        x: i32,
+        y: i32,
+        level: i16,
+    },
+    TroopRatioChange {
+        small_id: i16,
+        client_id: String,
+        target_troop_ratio: f32,
+    },
+}
+
+/// A parsed payload: the engine version from the header and every other row
+#[derive(Debug, Clone)]
+pub struct AnalysisPayload {
+    pub analysis_engine_version: String,
+    pub rows: Vec<AnalysisRow>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IngestError {
+    /// Something is wrong with what was uploaded
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Game {0} has not finished")]
+    GameNotFound(String),
+    #[error("Job {0} is not leased to this worker")]
+    LeaseLost(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for IngestError {
+    fn from(e: sqlx::Error) -> Self {
+        // Data exceptions (22) and constraint violations (23) come from the rows we were sent,
+        // like an unknown event type or two players with the same id
+        if let Some(db_err) = e.as_database_error()
+            && db_err
+                .code()
+                .is_some_and(|c| c.starts_with("22") || c.starts_with("23"))
+        {
+            return IngestError::Invalid(db_err.message().to_string());
+        }
+        IngestError::Other(e.into())
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> IngestError {
+    IngestError::Invalid(msg.into())
+}
+
+/// Parses an uploaded payload, which may be gzipped
+pub fn parse_payload(body: &[u8]) -> Result<AnalysisPayload, IngestError> {
+    let mut decompressed = Vec::new();
+    let body = if body.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::GzDecoder::new(body)
+            .take(MAX_PAYLOAD_BYTES as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| invalid(format!("Invalid gzip: {}", e)))?;
+        if decompressed.len() > MAX_PAYLOAD_BYTES {
+            return Err(invalid(format!(
+                "Analysis is larger than {} bytes",
+                MAX_PAYLOAD_BYTES
+            )));
+        }
+        &decompressed[..]
+    } else {
+        body
+    };
+    let body = std::str::from_utf8(body).map_err(|_| invalid("Analysis is not UTF-8"))?;
+
+    let mut version = None;
+    let mut rows = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: AnalysisRow =
+            serde_json::from_str(line).map_err(|e| invalid(format!("Line {}: {}", i + 1, e)))?;
+        match row {
+            AnalysisRow::Header {
+                analysis_engine_version,
+            } => {
+                if version.is_some() {
+                    return Err(invalid(format!("Line {}: more than one header", i + 1)));
+                }
+                version = Some(analysis_engine_version);
+            }
+            row => rows.push(row),
+        }
+    }
+
+    let analysis_engine_version = version
+        .filter(|v| !v.trim().is_empty())
+        .ok_or_else(|| invalid("Missing header with the analysis_engine_version"))?;
+
+    Ok(AnalysisPayload {
+        analysis_engine_version,
+        rows,
+    })
+}
+
+impl AnalysisPayload {
+    /// Checks every player in the analysis played the game, and that all other rows refer to
+    /// those players
+    pub fn validate(&self, record: &GameRecord) -> Result<(), IngestError> {
+        let mut ids = HashSet::new();
+        let mut small_ids = HashSet::new();
+        for row in &self.rows {
+            if let AnalysisRow::Player {
+                id,
+                client_id,
+                small_id,
+                ..
+            } = row
+            {
+                if !ids.insert(id.as_str()) {
+                    return Err(invalid(format!("Player {} is listed twice", id)));
+                }
+                if !small_ids.insert(*small_id) {
+                    return Err(invalid(format!("Small id {} is used twice", small_id)));
+                }
+                if let Some(client_id) = client_id
+                    && record.player(client_id).is_none()
+                {
+                    return Err(invalid(format!(
+                        "Player {} with client {} is not in the game",
+                        id, client_id
+                    )));
+                }
+            }
+        }
+
+        let check_client = |client_id: &str| {
+            if record.player(client_id).is_none() {
+                return Err(invalid(format!("Client {} is not in the game", client_id)));
+            }
+            Ok(())
+        };
+        let check_small_id = |small_id: &i16| {
+            if !small_ids.contains(small_id) {
+                return Err(invalid(format!(
+                    "Small id {} is not one of the players",
+                    small_id
+                )));
+            }
+            Ok(())
+        };
+
+        for row in &self.rows {
+            match row {
+                AnalysisRow::PlayerUpdate { small_id, .. } => check_small_id(small_id)?,
+                AnalysisRow::SpawnLocation { client_id, .. } => check_client(client_id)?,
+                AnalysisRow::ConstructionEvent {
+                    client_id,
+                    small_id,
+                    ..
+                }
+                | AnalysisRow::TroopRatioChange {
+                    client_id,
+                    small_id,
+                    ..
+                } => {
+                    check_client(client_id)?;
+                    check_small_id(small_id)?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A value written in the text format of `COPY`
+trait CopyValue {
+    fn write_copy(&self, out: &mut Vec<u8>);
+}
+
+impl CopyValue for str {
+    fn write_copy(&self, out: &mut Vec<u8>) {
+        for b in self.bytes() {
+            match b {
+                b'\\' => out.extend_from_slice(b"\\\\"),
+                b'\t' => out.extend_from_slice(b"\\t"),
+                b'\n' => out.extend_from_slice(b"\\n"),
+                b'\r' => out.extend_from_slice(b"\\r"),
+                b => out.push(b),
+            }
+        }
+    }
+}
+
+impl CopyValue for String {
+    fn write_copy(&self, out: &mut Vec<u8>) {
+        self.as_str().write_copy(out)
+    }
+}
+
+impl CopyValue for serde_json::Value {
+    fn write_copy(&self, out: &mut Vec<u8>) {
+        self.to_string().write_copy(out)
+    }
+}
+
+/// Only used for `BIT(1)` columns
+impl CopyValue for bool {
+    fn write_copy(&self, out: &mut Vec<u8>) {
+        out.push(if *self { b'1' } else { b'0' });
+    }
+}
+
+macro_rules! copy_value_display {
+    ($($t:ty),*) => {
+        $(impl CopyValue for $t {
+            fn write_copy(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(self.to_string().as_bytes());
+            }
+        })*
+    };
+}
+copy_value_display!(i16, i32, f32);
+
+impl<T: CopyValue> CopyValue for Option<T> {
+    fn write_copy(&self, out: &mut Vec<u8>) {
+        match self {
+            Some(v) => v.write_copy(out),
+            None => out.extend_from_slice(b"\\N"),
+        }
+    }
+}
+
+/// The rows for one table, ready to send to `COPY ... FROM STDIN`
+struct CopyTable {
+    statement: &'static str,
+    data: Vec<u8>,
+    rows: usize,
+}
+
+impl CopyTable {
+    fn new(statement: &'static str) -> Self {
+        CopyTable {
+            statement,
+            data: Vec::new(),
+            rows: 0,
+        }
+    }
+
+    fn row(&mut self, game_id: &str, fields: &[&dyn CopyValue]) {
+        game_id.write_copy(&mut self.data);
+        for field in fields {
+            self.data.push(b'\t');
+            field.write_copy(&mut self.data);
+        }
+        self.data.push(b'\n');
+        self.rows += 1;
+    }
+
+    async fn send(self, txn: &mut Transaction<'_, Postgres>) -> Result<u64, sqlx::Error> {
+        if self.rows == 0 {
+            return Ok(0);
+        }
+        let mut copy = txn.copy_in_raw(self.statement).await?;
+        copy.send(self.data).await?;
+        copy.finish().await
+    }
+}
+
+/// Tables an analysis writes to, which are emptied for the game before saving a new one
+const ANALYSIS_TABLES: &[&str] = &[
+    "analysis_1.players",
+    "analysis_1.packed_player_updates",
+    "analysis_1.general_events",
+    "analysis_1.display_events",
+    "analysis_1.spawn_locations",
+    "analysis_1.construction_events",
+    "analysis_1.troop_ratio_change",
+    "analysis_1.completed_analysis",
+];
+
+fn copy_tables(game_id: &str, payload: &AnalysisPayload) -> Vec<CopyTable> {
+    let mut players = CopyTable::new(
+        "COPY analysis_1.players (game_id, id, client_id, small_id, player_type, name, flag, team) FROM STDIN",
+    );
+    let mut player_updates = CopyTable::new(
+        "COPY analysis_1.packed_player_updates (game_id, small_id, tick, player_alive, player_connected, tiles_owned, gold, workers, troops) FROM STDIN",
+    );
+    let mut general_events = CopyTable::new(
+        "COPY analysis_1.general_events (game_id, tick, event_type, data) FROM STDIN",
+    );
+    let mut display_events = CopyTable::new(
+        "COPY analysis_1.display_events (game_id, tick, message_type, message, player_id, gold_amount) FROM STDIN",
+    );
+    let mut spawn_locations = CopyTable::new(
+        "COPY analysis_1.spawn_locations (game_id, client_id, tick, x, y, previous_spawns) FROM STDIN",
+    );
+    let mut construction_events = CopyTable::new(
+        "COPY analysis_1.construction_events (game_id, client_id, small_id, tick, unit_type, x, y, level) FROM STDIN",
+    );
+    let mut troop_ratio_changes = CopyTable::new(
+        "COPY analysis_1.troop_ratio_change (game_id, small_id, client_id, target_troop_ratio) FROM STDIN",
+    );
+
+    let no_spawns = serde_json::Value::Array(vec![]);
+    for row in &payload.rows {
+        match row {
+            AnalysisRow::Header { .. } => {}
+            AnalysisRow::Player {
+                id,
+                client_id,
+                small_id,
+                player_type,
+                name,
+                flag,
+                team,
+            } => players.row(
+                game_id,
+                &[id, client_id, small_id, player_type, name, flag, team],
+            ),
+            AnalysisRow::PlayerUpdate {
+                small_id,
+                tick,
+                player_alive,
+                player_connected,
+                tiles_owned,
+                gold,
+                workers,
+                troops,
+            } => player_updates.row(
+                game_id,
+                &[
+                    small_id,
+                    tick,
+                    player_alive,
+                    player_connected,
+                    tiles_owned,
+                    gold,
+                    workers,
+                    troops,
+                ],
+            ),
+            AnalysisRow::GeneralEvent {
+                tick,
+                event_type,
+                data,
+            } => general_events.row(game_id, &[tick, event_type, data]),
+            AnalysisRow::DisplayEvent {
+                tick,
+                message_type,
+                message,
+                player_id,
+                gold_amount,
+            } => display_events.row(
+                game_id,
+                &[tick, message_type, message, player_id, gold_amount],
+            ),
+            AnalysisRow::SpawnLocation {
+                client_id,
+                tick,
+                x,
+                y,
+                previous_spawns,
+            } => spawn_locations.row(
+                game_id,
+                &[
+                    client_id,
+                    tick,
+                    x,
+                    y,
+                    previous_spawns.as_ref().unwrap_or(&no_spawns),
+                ],
+            ),
+            AnalysisRow::ConstructionEvent {
+                client_id,
+                small_id,
+                tick,
+                unit_type,
+                x,
+                y,
+                level,
+            } => construction_events.row(
+                game_id,
+                &[client_id, small_id, tick, unit_type, x, y, level],
+            ),
+            AnalysisRow::TroopRatioChange {
+                small_id,
+                client_id,
+                target_troop_ratio,
+            } => troop_ratio_changes.row(game_id, &[small_id, client_id, target_troop_ratio]),
+        }
+    }
+
+    vec![
+        players,
+        player_updates,
+        general_events,
+        display_events,
+        spawn_locations,
+        construction_events,
+        troop_ratio_changes,
+    ]
+}
+
+/// Checks the payload against the game and replaces any previous analysis with it. Returns how
+/// many rows were saved.
+///
+/// With a `worker_id`, the game's job must be Running under that worker. Its queue row stays
+/// locked until the analysis is saved, so the lease can't expire and be claimed by someone else
+/// halfway through.
+pub async fn ingest_analysis(
+    db: &PgPool,
+    game_id: &str,
+    payload: &AnalysisPayload,
+    worker_id: Option<&str>,
+) -> Result<u64, IngestError> {
+    let game = sqlx::query!(
+        "SELECT result_json FROM finished_games WHERE game_id = $1 AND is_ok",
+        game_id
+    )
+    .fetch_optional(db)
+    .await?
+    .and_then(|g| g.result_json)
+    .ok_or_else(|| IngestError::GameNotFound(game_id.to_string()))?;
+    let record: GameRecord =
+        serde_json::from_value(game).context("Saved game record is invalid")?;
+    payload.validate(&record)?;
+
+    let mut txn = db.begin().await?;
+
+    if let Some(worker_id) = worker_id {
+        sqlx::query!(
+            "SELECT game_id FROM analysis_queue
+            WHERE game_id = $1 AND worker_id = $2 AND status = 'Running'
+            FOR UPDATE",
+            game_id,
+            worker_id,
+        )
+        .fetch_optional(&mut *txn)
+        .await?
+        .ok_or_else(|| IngestError::LeaseLost(game_id.to_string()))?;
+    }
+
+    for table in ANALYSIS_TABLES {
+        sqlx::query(&format!("DELETE FROM {} WHERE game_id = $1", table))
+            .bind(game_id)
+            .execute(&mut *txn)
+            .await?;
+    }
+
+    let mut saved = 0;
+    for table in copy_tables(game_id, payload) {
+        saved += table.send(&mut txn).await?;
+    }
+
+    let intents = super::intents::analyze_intents(&record);
+    super::intents::write_intent_analysis(&mut txn, game_id, &intents).await?;
+
+    sqlx::query!(
+        "INSERT INTO analysis_1.completed_analysis (game_id, analysis_engine_version)
+        VALUES ($1, $2)",
+        game_id,
+        payload.analysis_engine_version,
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    txn.commit().await?;
+
+    Ok(saved)
+}
+
+/// The saved analysis of a game as a payload [`parse_payload`] accepts. `None` if the game
+/// hasn't been analyzed.
+pub async fn export_analysis(db: &PgPool, game_id: &str) -> anyhow::Result<Option<String>> {
+    let Some(analysis_engine_version) = sqlx::query_scalar!(
+        "SELECT analysis_engine_version FROM analysis_1.completed_analysis WHERE game_id = $1",
+        game_id
+    )
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let mut rows = vec![AnalysisRow::Header {
+        analysis_engine_version,
+    }];
+
+    let players = sqlx::query!(
+        r#"SELECT id, client_id, small_id, player_type::TEXT AS "player_type!", name, flag, team
+        FROM analysis_1.players
+        WHERE game_id = $1
+        ORDER BY small_id"#,
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(players.into_iter().map(|r| AnalysisRow::Player {
+        id: r.id,
+        client_id: r.client_id,
+        small_id: r.small_id,
+        player_type: r.player_type,
+        name: r.name,
+        flag: r.flag,
+        team: r.team,
+    }));
+
+    let player_updates = sqlx::query!(
+        r#"SELECT
+            small_id, tick,
+            player_alive = B'1' AS "player_alive!",
+            player_connected = B'1' AS "player_connected!",
+            tiles_owned, gold, workers, troops
+        FROM analysis_1.packed_player_updates
+        WHERE game_id = $1
+        ORDER BY tick, small_id"#,
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(
+        player_updates
+            .into_iter()
+            .map(|r| AnalysisRow::PlayerUpdate {
+                small_id: r.small_id,
+                tick: r.tick,
+                player_alive: r.player_alive,
+                player_connected: r.player_connected,
+                tiles_owned: r.tiles_owned,
+                gold: r.gold,
+                workers: r.workers,
+                troops: r.troops,
+            }),
+    );
+
+    let general_events = sqlx::query!(
+        r#"SELECT tick, event_type::TEXT AS "event_type!", data
+        FROM analysis_1.general_events
+        WHERE game_id = $1
+        ORDER BY tick"#,
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(
+        general_events
+            .into_iter()
+            .map(|r| AnalysisRow::GeneralEvent {
+                tick: r.tick,
+                event_type: r.event_type,
+                data: r.data,
+            }),
+    );
+
+    let display_events = sqlx::query!(
+        "SELECT tick, message_type, message, player_id, gold_amount
+        FROM analysis_1.display_events
+        WHERE game_id = $1
+        ORDER BY tick",
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(
+        display_events
+            .into_iter()
+            .map(|r| AnalysisRow::DisplayEvent {
+                tick: r.tick,
+                message_type: r.message_type,
+                message: r.message,
+                player_id: r.player_id,
+                gold_amount: r.gold_amount,
+            }),
+    );
+
+    let spawn_locations = sqlx::query!(
+        "SELECT client_id, tick, x, y, previous_spawns
+        FROM analysis_1.spawn_locations
+        WHERE game_id = $1
+        ORDER BY tick",
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(
+        spawn_locations
+            .into_iter()
+            .map(|r| AnalysisRow::SpawnLocation {
+                client_id: r.client_id,
+                tick: r.tick,
+                x: r.x,
+                y: r.y,
+                previous_spawns: r.previous_spawns,
+            }),
+    );
+
+    let construction_events = sqlx::query!(
+        "SELECT client_id, small_id, tick, unit_type, x, y, level
+        FROM analysis_1.construction_events
+        WHERE game_id = $1
+        ORDER BY tick",
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(
+        construction_events
+            .into_iter()
+            .map(|r| AnalysisRow::ConstructionEvent {
+                client_id: r.client_id,
+                small_id: r.small_id,
+                tick: r.tick,
+                unit_type: r.unit_type,
+                x: r.x,
+                y: r.y,
+                level: r.level,
+            }),
+    );
+
+    let troop_ratio_changes = sqlx::query!(
+        "SELECT small_id, client_id, target_troop_ratio
+        FROM analysis_1.troop_ratio_change
+        WHERE game_id = $1
+        ORDER BY small_id",
+        game_id
+    )
+    .fetch_all(db)
+    .await?;
+    rows.extend(
+        troop_ratio_changes
+            .into_iter()
+            .map(|r| AnalysisRow::TroopRatioChange {
+                small_id: r.small_id,
+                client_id: r.client_id,
+                target_troop_ratio: r.target_troop_ratio,
+            }),
+    );
+
+    let mut out = String::new();
+    for row in rows {
+        out.push_str(&serde_json::to_string(&row)?);
+        out.push('\n');
+    }
+
+    Ok(Some(out))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mygame() -> GameRecord {
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn payload_for(record: &GameRecord) -> String {
+        let client_id = &record.info.players[0].client_id;
+        [
+            r#"{"table":"header","analysis_engine_version":"v1"}"#.to_string(),
+            format!(
+                r#"{{"table":"player","id":"AAAAAAAA","client_id":"{}","small_id":1,"player_type":"HUMAN","name":"bob\tthe\\builder"}}"#,
+                client_id
+            ),
+            r#"{"table":"player","id":"BBBBBBBB","client_id":null,"small_id":2,"player_type":"BOT","name":"bot"}"#.to_string(),
+            r#"{"table":"player_update","small_id":1,"tick":10,"player_alive":true,"player_connected":false,"tiles_owned":1,"gold":2,"workers":3,"troops":4}"#.to_string(),
+            format!(
+                r#"{{"table":"spawn_location","client_id":"{}","tick":3,"x":10,"y":20}}"#,
+                client_id
+            ),
+            String::new(),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn test_parse_and_validate() {
+        let record = mygame();
+        let payload = parse_payload(payload_for(&record).as_bytes()).unwrap();
+        assert_eq!(payload.analysis_engine_version, "v1");
+        assert_eq!(payload.rows.len(), 4);
+        payload.validate(&record).unwrap();
+
+        // Gzipped is the same
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gz, payload_for(&record).as_bytes()).unwrap();
+        let gzipped = parse_payload(&gz.finish().unwrap()).unwrap();
+        assert_eq!(gzipped.rows, payload.rows);
+
+        // A player update for someone who isn't a player
+        let mut bad = payload.clone();
+        bad.rows.push(AnalysisRow::PlayerUpdate {
+            small_id: 99,
+            tick: 1,
+            player_alive: true,
+            player_connected: true,
+            tiles_owned: 0,
+            gold: 0,
+            workers: 0,
+            troops: 0,
+        });
+        assert!(matches!(
+            bad.validate(&record),
+            Err(IngestError::Invalid(_))
+        ));
+
+        // A spawn for a client that wasn't in the game
+        let mut bad = payload.clone();
+        bad.rows.push(AnalysisRow::SpawnLocation {
+            client_id: "NOTHERE1".to_string(),
+            tick: 1,
+            x: 0,
+            y: 0,
+            previous_spawns: None,
+        });
+        assert!(bad.validate(&record).is_err());
+
+        assert!(parse_payload(b"").is_err());
+        assert!(parse_payload(b"{\"table\":\"nope\"}").is_err());
+        let two_headers = format!(
+            "{}\n{}",
+            r#"{"table":"header","analysis_engine_version":"v1"}"#,
+            payload_for(&record)
+        );
+        assert!(parse_payload(two_headers.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_copy_text_format() {
+        let record = mygame();
+        let payload = parse_payload(payload_for(&record).as_bytes()).unwrap();
+        let tables = copy_tables("GAME0001", &payload);
+
+        let players = std::str::from_utf8(&tables[0].data).unwrap();
+        let client_id = &record.info.players[0].client_id;
+        assert_eq!(
+            players,
+            format!(
+                "GAME0001\tAAAAAAAA\t{}\t1\tHUMAN\tbob\\tthe\\\\builder\t\\N\t\\N\n\
+                 GAME0001\tBBBBBBBB\t\\N\t2\tBOT\tbot\t\\N\t\\N\n",
+                client_id
+            )
+        );
+
+        let updates = std::str::from_utf8(&tables[1].data).unwrap();
+        assert_eq!(updates, "GAME0001\t1\t10\t1\t0\t1\t2\t3\t4\n");
+
+        let spawns = std::str::from_utf8(&tables[4].data).unwrap();
+        assert_eq!(spawns, format!("GAME0001\t{}\t3\t10\t20\t[]\n", client_id));
+        assert_eq!(tables.iter().map(|t| t.rows).sum::<usize>(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_export_what_was_ingested() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let game = crate::game_record::FinishedGame::from_value(json).unwrap();
+        let game_id = game.record.info.game_id.clone();
+        crate::import::import_record(&db.pool, &game).await.unwrap();
+
+        assert!(export_analysis(&db.pool, &game_id).await.unwrap().is_none());
+
+        let payload = parse_payload(payload_for(&game.record).as_bytes()).unwrap();
+        ingest_analysis(&db.pool, &game_id, &payload, None)
+            .await
+            .unwrap();
+
+        let exported = export_analysis(&db.pool, &game_id).await.unwrap().unwrap();
+        let exported = parse_payload(exported.as_bytes()).unwrap();
+        assert_eq!(exported.analysis_engine_version, "v1");
+        // Spawns without previous spawns are saved with an empty list
+        let mut expected = payload.rows.clone();
+        for row in &mut expected {
+            if let AnalysisRow::SpawnLocation {
+                previous_spawns, ..
+            } = row
+            {
+                previous_spawns.get_or_insert(serde_json::json!([]));
+            }
+        }
+        assert_eq!(exported.rows, expected);
+    }
+}
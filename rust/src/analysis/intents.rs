@@ -0,0 +1,334 @@
+//! Analysis computed in rust directly from the `turns[].intents` of a [`GameRecord`].
+//!
+//! This does not need the simulator, so it is run for every game as soon as it is saved, and again
+//! whenever the full analysis of a game is saved. That also covers games that were saved before
+//! this analysis existed.
+
+use std::collections::{BTreeMap, HashMap};
+
+use schemars::JsonSchema;
+use sqlx::PgPool;
+
+use crate::game_record::{GameRecord, Intent};
+
+/// OpenFront runs one turn every 100ms
+pub const TURNS_PER_MINUTE: u32 = 600;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct ResIntentsOverGame {
+    pub players: Vec<PlayerIntents>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct PlayerIntents {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub total_intents: u32,
+    /// Index is the minute of the game
+    pub intents_per_minute: Vec<u32>,
+    pub attacks: u32,
+    pub attack_troops_sent: u64,
+    pub boats: u32,
+    pub boat_troops_sent: u64,
+    /// Unit type -> number of build intents
+    pub builds: BTreeMap<String, u32>,
+    /// Unit type -> number of upgrade intents
+    pub upgrades: BTreeMap<String, u32>,
+    pub troop_ratio_changes: Vec<TroopRatioChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct TroopRatioChange {
+    pub turn: u32,
+    pub ratio: f64,
+}
+
+/// Walks every intent in the game and totals them up per player
+pub fn analyze_intents(record: &GameRecord) -> ResIntentsOverGame {
+    let mut players: HashMap<&str, PlayerIntents> = HashMap::new();
+
+    for (turn, intent) in record.intents() {
+        let Some(client_id) = intent.client_id() else {
+            continue;
+        };
+
+        let player = players.entry(client_id).or_insert_with(|| PlayerIntents {
+            client_id: client_id.to_string(),
+            username: record.player(client_id).map(|p| p.username.clone()),
+            ..Default::default()
+        });
+
+        let minute = (turn / TURNS_PER_MINUTE) as usize;
+        if player.intents_per_minute.len() <= minute {
+            player.intents_per_minute.resize(minute + 1, 0);
+        }
+        player.intents_per_minute[minute] += 1;
+        player.total_intents += 1;
+
+        match intent {
+            Intent::Attack { troops, .. } => {
+                player.attacks += 1;
+                player.attack_troops_sent += troops.max(0.0).round() as u64;
+            }
+            Intent::Boat { troops, .. } => {
+                player.boats += 1;
+                player.boat_troops_sent += troops.max(0.0).round() as u64;
+            }
+            Intent::BuildUnit { unit, .. } => {
+                *player.builds.entry(unit.clone()).or_default() += 1;
+            }
+            Intent::UpgradeStructure { unit, .. } => {
+                *player.upgrades.entry(unit.clone()).or_default() += 1;
+            }
+            Intent::TroopRatio { ratio, .. } => {
+                player.troop_ratio_changes.push(TroopRatioChange {
+                    turn,
+                    ratio: *ratio,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut players: Vec<_> = players.into_values().collect();
+    players.sort_by_key(|p| std::cmp::Reverse(p.total_intents));
+
+    ResIntentsOverGame { players }
+}
+
+/// Replaces any previous intent analysis for this game
+pub async fn save_intent_analysis(
+    db: &PgPool,
+    game_id: &str,
+    res: &ResIntentsOverGame,
+) -> anyhow::Result<()> {
+    let mut txn = db.begin().await?;
+    write_intent_analysis(&mut txn, game_id, res).await?;
+    txn.commit().await?;
+
+    Ok(())
+}
+
+/// [`save_intent_analysis`] as part of a larger transaction
+pub async fn write_intent_analysis(
+    txn: &mut sqlx::PgConnection,
+    game_id: &str,
+    res: &ResIntentsOverGame,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM analysis_1.player_intents WHERE game_id = $1",
+        game_id
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    for player in &res.players {
+        let intents_per_minute: Vec<i32> = player
+            .intents_per_minute
+            .iter()
+            .map(|&n| n as i32)
+            .collect();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO analysis_1.player_intents (
+                game_id, client_id, username, total_intents, intents_per_minute,
+                attacks, attack_troops_sent, boats, boat_troops_sent,
+                builds, upgrades, troop_ratio_changes
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            "#,
+            game_id,
+            player.client_id,
+            player.username,
+            player.total_intents as i32,
+            &intents_per_minute,
+            player.attacks as i32,
+            player.attack_troops_sent as i64,
+            player.boats as i32,
+            player.boat_troops_sent as i64,
+            serde_json::to_value(&player.builds)?,
+            serde_json::to_value(&player.upgrades)?,
+            serde_json::to_value(&player.troop_ratio_changes)?,
+        )
+        .execute(&mut *txn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Reads the intent analysis for a game. `None` if the game isn't known, or was saved before this
+/// analysis existed and hasn't been analyzed since.
+pub async fn get_intents_over_game(
+    db: PgPool,
+    game_id: &str,
+) -> anyhow::Result<Option<ResIntentsOverGame>> {
+    // Ensure gameid is 8 chars and alphanumeric
+    if game_id.len() != 8 || !game_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(anyhow::anyhow!("Invalid game_id: {}", game_id));
+    }
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            client_id,
+            username,
+            total_intents,
+            intents_per_minute,
+            attacks,
+            attack_troops_sent,
+            boats,
+            boat_troops_sent,
+            builds,
+            upgrades,
+            troop_ratio_changes
+        FROM
+            analysis_1.player_intents
+        WHERE
+            game_id = $1::char(8)
+        ORDER BY total_intents DESC
+        "#,
+        game_id
+    )
+    .fetch_all(&db)
+    .await?;
+
+    if rows.is_empty() {
+        return Ok(None);
+    }
+
+    let mut players = Vec::with_capacity(rows.len());
+    for row in rows {
+        players.push(PlayerIntents {
+            client_id: row.client_id,
+            username: row.username,
+            total_intents: row.total_intents as u32,
+            intents_per_minute: row
+                .intents_per_minute
+                .into_iter()
+                .map(|n| n as u32)
+                .collect(),
+            attacks: row.attacks as u32,
+            attack_troops_sent: row.attack_troops_sent as u64,
+            boats: row.boats as u32,
+            boat_troops_sent: row.boat_troops_sent as u64,
+            builds: serde_json::from_value(row.builds)?,
+            upgrades: serde_json::from_value(row.upgrades)?,
+            troop_ratio_changes: serde_json::from_value(row.troop_ratio_changes)?,
+        });
+    }
+
+    Ok(Some(ResIntentsOverGame { players }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_analyze_intents() {
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let record: GameRecord = serde_json::from_value(json.clone()).unwrap();
+        let res = analyze_intents(&record);
+
+        let all_intents: Vec<&serde_json::Value> = json["turns"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|t| t["intents"].as_array().unwrap())
+            .collect();
+        let count_of = |client_id: &str, ty: &str| {
+            all_intents
+                .iter()
+                .filter(|i| i["clientID"] == client_id && i["type"] == ty)
+                .count() as u32
+        };
+
+        let total: u32 = res.players.iter().map(|p| p.total_intents).sum();
+        assert_eq!(total as usize, all_intents.len());
+
+        for player in &res.players {
+            let id = player.client_id.as_str();
+            assert_eq!(
+                player.intents_per_minute.iter().sum::<u32>(),
+                player.total_intents
+            );
+            assert_eq!(player.attacks, count_of(id, "attack"));
+            assert_eq!(player.boats, count_of(id, "boat"));
+            assert_eq!(
+                player.builds.values().sum::<u32>(),
+                count_of(id, "build_unit")
+            );
+            assert_eq!(
+                player.upgrades.values().sum::<u32>(),
+                count_of(id, "upgrade_structure")
+            );
+            assert_eq!(
+                player.troop_ratio_changes.len() as u32,
+                count_of(id, "troop_ratio")
+            );
+        }
+
+        // The winner of this game is the only human player
+        let winner = res
+            .players
+            .iter()
+            .find(|p| p.client_id == "7B35GWaD")
+            .unwrap();
+        assert_eq!(winner.username.as_deref(), Some("Anon842"));
+        assert!(winner.attack_troops_sent > 0);
+    }
+
+    #[tokio::test]
+    async fn test_intents_are_saved_by_the_pipeline() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let game = crate::game_record::FinishedGame::from_value(json).unwrap();
+        let game_id = game.record.info.game_id.clone();
+
+        crate::import::import_record(&db.pool, &game).await.unwrap();
+        let saved = get_intents_over_game(db.pool.clone(), &game_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            saved.players.len(),
+            analyze_intents(&game.record).players.len()
+        );
+
+        // Games saved before this analysis existed have none until they are analyzed, reading
+        // doesn't create it
+        sqlx::query("DELETE FROM analysis_1.player_intents")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(
+            get_intents_over_game(db.pool.clone(), &game_id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let payload = super::super::ingest::parse_payload(
+            br#"{"table":"header","analysis_engine_version":"v1"}"#,
+        )
+        .unwrap();
+        super::super::ingest::ingest_analysis(&db.pool, &game_id, &payload, None)
+            .await
+            .unwrap();
+        let analyzed = get_intents_over_game(db.pool.clone(), &game_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(analyzed.players.len(), saved.players.len());
+
+        assert!(
+            get_intents_over_game(db.pool.clone(), "nogame01")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -506,7 +506,7 @@ pub async fn get_game_players(db: PgPool, game_id: &str) -> anyhow::Result<ResPl
             player_type: row.player_type,
             name: row.name,
             flag: row.flag,
-            team: row.team.map(|t| t as i16),
+            team: row.team,
             spawn_info,
         };
         players.push(player);
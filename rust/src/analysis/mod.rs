@@ -1,5 +1,7 @@
 //!This module contains functions to retrieve differente analysis data to be used in the API.
 pub mod api;
+pub mod ingest;
+pub mod intents;
 pub mod methods;
 
 // On the javascript side, we need to compress some big floats into the range of small integers
@@ -31,7 +33,7 @@ pub mod methods;
 
 pub fn decompress_value_from_db(value: i16) -> u64 {
     let encoded = ((value as i32) + 32768) as u16;
-    let max_input_log = (1_000_000_000_000u64 as f64 + 1.0).log10();
+    let max_input_log = (1_000_000_000_000f64 + 1.0).log10();
     let norm = encoded as f64 / 65535.0;
 
     (10f64.powf(norm * max_input_log) - 1.0).round() as u64
@@ -53,7 +55,7 @@ mod test {
     #[test]
     fn test_decompress_value_from_db() {
         let within_1percent = |a: u64, b: u64| {
-            let diff = if a > b { a - b } else { b - a };
+            let diff = a.abs_diff(b);
             diff <= (a / 100) // 1% tolerance
         };
         //assert_eq!(decompress_value_from_db(-32768), 0);
@@ -16,13 +16,22 @@ use sqlx::PgPool;
 use tower_http::cors::CorsLayer;
 use tracing::info;
 
+pub mod admin;
 pub mod openfrontapi;
+pub mod sessions;
+pub mod workers;
 
 use crate::{
     AnalysisQueueStatus, analysis,
-    api::openfrontapi::{OpenFrontAPI, PublicLobbiesResponse},
-    database::{APIAnalysisQueueEntry, APIFinishedGame, APIGetLobby, APIGetLobbyWithConfig},
-    oauth::APIUser,
+    api::openfrontapi::{OpenFrontAPI, OpenFrontClient, PublicLobbiesResponse},
+    database::{
+        APIAnalysisAttempt, APIAnalysisJob, APIAnalysisQueueEntry, APIFinishedGame, APIGetLobby,
+        APIGetLobbyWithConfig, APILobbyHistory, LobbySnapshot,
+    },
+    game_record::GameRecord,
+    import,
+    oauth::{APIUser, Permission},
+    settings::Settings,
     tasks,
 };
 use anyhow::Result;
@@ -64,23 +73,70 @@ async fn lobbies_id_handler(
 
     Ok(Json(lobby))
 }
+
+async fn lobbies_id_history_handler(
+    Extension(database): Extension<PgPool>,
+    Path(id): Path<String>,
+) -> Result<Json<APILobbyHistory>, Response> {
+    let lobby = sqlx::query!(
+        r#"SELECT game_id, game_map, teams, max_players FROM lobbies WHERE game_id = $1"#,
+        id
+    )
+    .fetch_one(&database)
+    .await
+    .map_err(|e| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from(format!("Lobby not found: {}", e)))
+            .expect("Failed to build response for error message")
+    })?;
+
+    let snapshots = sqlx::query_as!(
+        LobbySnapshot,
+        r#"SELECT
+            seen_unix_sec, num_clients, ms_until_start
+        FROM
+            lobby_snapshots
+        WHERE game_id = $1
+        ORDER BY seen_unix_sec ASC"#,
+        id
+    )
+    .fetch_all(&database)
+    .await
+    .map_err(|e| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(axum::body::Body::from(format!(
+                "Failed to load lobby history: {}",
+                e
+            )))
+            .expect("Failed to build response for error message")
+    })?;
+
+    Ok(Json(APILobbyHistory {
+        game_id: lobby.game_id,
+        game_map: lobby.game_map,
+        teams: lobby.teams.into(),
+        max_players: lobby.max_players,
+        snapshots,
+    }))
+}
+
 async fn new_lobbies_handler(
     Extension(database): Extension<PgPool>,
     Json(body): Json<PublicLobbiesResponse>,
 ) -> Result<String, Response> {
-    if let Some(lobby) = body.lobbies.first() {
-        tasks::insert_new_game(&lobby, &database)
-            .await
-            .map_err(|e| {
-                axum::response::Response::builder()
-                    .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(axum::body::Body::from(format!(
-                        "Failed to insert new game: {}",
-                        e
-                    )))
-                    .expect("Failed to build response for error message")
-            })?;
-    }
+    tasks::insert_new_lobbies(&body.lobbies, &database)
+        .await
+        .map_err(|e| {
+            axum::response::Response::builder()
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::from(format!(
+                    "Failed to insert new game: {}",
+                    e
+                )))
+                .expect("Failed to build response for error message")
+        })?;
 
     Ok("Lobbies processed successfully".to_string())
 }
@@ -205,34 +261,233 @@ async fn game_handler(
             .expect("Failed to build response for error message")
     })?;
 
+    // Games that errored upstream are stored with the error response instead of a game record.
+    // The record is sent as it was stored, parsing only checks that it is one.
+    GameRecord::deserialize(&lobby.result_json).map_err(|e| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from(format!(
+                "Game record not available: {}",
+                e
+            )))
+            .expect("Failed to build response for error message")
+    })?;
+
     Ok(Json(lobby.result_json))
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+struct GameImportParams {
+    /// Also add the game to the analysis queue
+    #[serde(default)]
+    analyze: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+struct APIGameImportResult {
+    game_id: String,
+    /// False if we already had this game
+    inserted: bool,
+    queued_for_analysis: bool,
+}
+
+/// Upload a game record as JSON or gzipped JSON, for games the lobby poller never saw.
+async fn game_import_handler(
+    Extension(database): Extension<PgPool>,
+    Query(params): Query<GameImportParams>,
+    user: APIUser,
+    body: axum::body::Bytes,
+) -> Result<Json<APIGameImportResult>, Response> {
+    user.require(Permission::ImportGames)?;
+
+    let game = import::decode_upload(&body).map_err(|e| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::BAD_REQUEST)
+            .body(axum::body::Body::from(format!("{:#}", e)))
+            .expect("Failed to build response for error message")
+    })?;
+
+    let outcome = import::import_record(&database, &game).await.map_err(|e| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(axum::body::Body::from(format!(
+                "Failed to import game: {:#}",
+                e
+            )))
+            .expect("Failed to build response for error message")
+    })?;
+
+    let game_id = game.record.info.game_id;
+    info!(
+        user.user_id,
+        ?outcome,
+        "Game {} uploaded by {}",
+        game_id,
+        user.username
+    );
+
+    let queued_for_analysis = if params.analyze {
+        import::enqueue_analysis(
+            &database,
+            &game_id,
+            Some(&user.user_id),
+            import::REQUESTED_PRIORITY,
+        )
+        .await
+        .map_err(into_error_resp)?
+    } else {
+        false
+    };
+
+    Ok(Json(APIGameImportResult {
+        game_id,
+        inserted: outcome == import::ImportOutcome::Inserted,
+        queued_for_analysis,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+struct GameAnalyzeParams {
+    /// Analyze the game again even if it already has an analysis
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+struct APIAnalysisRequest {
+    game_id: String,
+    /// Pending or Running for a job in the queue, or CompletedAlready if the game already has an
+    /// analysis and nothing was queued
+    status: AnalysisQueueStatus,
+    /// Where a Pending job is in the queue, starting at 1
+    position: Option<i64>,
+    /// False if the game already had a job, or an analysis
+    queued: bool,
+}
+
+/// The Pending or Running job for a game, if it has one
+async fn active_analysis_job(
+    database: &PgPool,
+    game_id: &str,
+) -> Result<Option<APIAnalysisRequest>, sqlx::Error> {
+    let job = sqlx::query!(
+        r#"
+        SELECT
+            aq.status AS "status: AnalysisQueueStatus",
+            o.position AS "position?"
+        FROM analysis_queue aq
+            LEFT JOIN analysis_queue_order o ON o.game_id = aq.game_id
+        WHERE
+            aq.game_id = $1
+            AND aq.status IN ('Pending', 'Running')
+        "#,
+        game_id
+    )
+    .fetch_optional(database)
+    .await?;
+
+    Ok(job.map(|job| APIAnalysisRequest {
+        game_id: game_id.to_string(),
+        status: job.status,
+        position: job.position,
+        queued: false,
+    }))
+}
+
+/// Queue a game for analysis. Asking again while it is queued returns the same job, and a game
+/// that was already analyzed is only queued again with `?force=true`.
 async fn game_analyze_handler(
     Extension(database): Extension<PgPool>,
+    Extension(settings): Extension<Settings>,
     Path(game_id): Path<String>,
+    Query(params): Query<GameAnalyzeParams>,
     user: APIUser,
-) -> Result<(), Response> {
-    //Insert into analysis_queue
+) -> Result<Json<APIAnalysisRequest>, Response> {
+    if let Some(job) = active_analysis_job(&database, &game_id)
+        .await
+        .map_err(into_error_resp)?
+    {
+        return Ok(Json(job));
+    }
+
+    if !params.force {
+        let analyzed = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM analysis_1.completed_analysis WHERE game_id = $1
+            ) AS "exists!""#,
+            game_id
+        )
+        .fetch_one(&database)
+        .await
+        .map_err(into_error_resp)?;
+        if analyzed {
+            return Ok(Json(APIAnalysisRequest {
+                game_id,
+                status: AnalysisQueueStatus::CompletedAlready,
+                position: None,
+                queued: false,
+            }));
+        }
+    }
+
+    // Keep one user from filling the queue. Their requests take turns, so two at once can't both
+    // see room for one more job.
+    let limit = settings.get().max_pending_analyses_per_user;
+    let mut txn = database.begin().await.map_err(into_error_resp)?;
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtextextended('analysis_queue_user:' || $1, 0))",
+        user.user_id,
+    )
+    .execute(&mut *txn)
+    .await
+    .map_err(into_error_resp)?;
+
+    // Someone else may have queued it since we looked, then their job is the one we return
     let res = sqlx::query!(
-        "INSERT INTO analysis_queue (game_id, requesting_user_id)
-         VALUES ($1, $2)",
+        "INSERT INTO analysis_queue (game_id, requesting_user_id, priority)
+        SELECT $1, $2, $3
+        WHERE (
+            SELECT COUNT(*) FROM analysis_queue
+            WHERE requesting_user_id = $2 AND status = 'Pending'
+        ) < $4
+        ON CONFLICT (game_id) WHERE status IN ('Pending', 'Running') DO NOTHING",
         game_id,
         user.user_id,
+        import::REQUESTED_PRIORITY,
+        limit,
     )
-    .execute(&database)
-    .await;
-
-    match res {
-        Ok(_) => Ok(()),
-        Err(e) => Err(axum::response::Response::builder()
+    .execute(&mut *txn)
+    .await
+    .map_err(|e| {
+        axum::response::Response::builder()
             .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
             .body(axum::body::Body::from(format!(
                 "Failed to queue analysis: {}",
                 e
             )))
-            .expect("Failed to build response for error message")),
+            .expect("Failed to build response for error message")
+    })?;
+    txn.commit().await.map_err(into_error_resp)?;
+
+    let Some(mut job) = active_analysis_job(&database, &game_id)
+        .await
+        .map_err(into_error_resp)?
+    else {
+        // Nothing was queued, by us or anyone else, so the user is at their limit
+        return Err(axum::response::Response::builder()
+            .status(axum::http::StatusCode::TOO_MANY_REQUESTS)
+            .body(axum::body::Body::from(format!(
+                "Too many of your games are waiting for analysis (at most {}). Try again once some of them are done.",
+                limit
+            )))
+            .expect("Failed to build response for error message"));
+    };
+    job.queued = res.rows_affected() > 0;
+    if job.queued {
+        info!(user.user_id, game_id, "{} queued analysis", user.username);
     }
+
+    Ok(Json(job))
 }
 
 async fn game_analyze_handler_delete(
@@ -240,9 +495,10 @@ async fn game_analyze_handler_delete(
     Path(game_id): Path<String>,
     user: APIUser,
 ) -> Result<(), Response> {
-    // Set status to cancelled
+    // Set status to cancelled. Finished jobs are the game's history and stay as they are.
     let res = sqlx::query!(
-        "UPDATE analysis_queue SET status = 'Cancelled' WHERE game_id = $1 AND requesting_user_id = $2",
+        "UPDATE analysis_queue SET status = 'Cancelled'
+        WHERE game_id = $1 AND requesting_user_id = $2 AND status IN ('Pending', 'Running')",
         game_id,
         user.user_id,
     )
@@ -287,6 +543,88 @@ fn into_error_resp(e: impl std::fmt::Display) -> Response {
         .expect("Failed to build response for error message")
 }
 
+/// A game's analysis job and the history of its attempts. Which worker ran it and how it failed
+/// are only shown to admins.
+async fn analysis_queue_job_handler(
+    Extension(database): Extension<PgPool>,
+    Path(game_id): Path<String>,
+    user: Option<APIUser>,
+) -> Result<Json<APIAnalysisJob>, Response> {
+    // Older rows for the game are earlier jobs, their attempts are all listed below
+    let job = sqlx::query!(
+        r#"
+        SELECT
+            aq.status AS "status: AnalysisQueueStatus",
+            aq.requested_unix_sec,
+            aq.started_unix_sec,
+            aq.priority,
+            aq.worker_id,
+            aq.lease_expires_unix_sec,
+            o.position AS "position?"
+        FROM analysis_queue aq
+            LEFT JOIN analysis_queue_order o ON o.game_id = aq.game_id
+        WHERE aq.game_id = $1
+        ORDER BY aq.requested_unix_sec DESC
+        LIMIT 1
+        "#,
+        game_id
+    )
+    .fetch_optional(&database)
+    .await
+    .map_err(into_error_resp)?
+    .ok_or_else(|| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from(format!(
+                "Game {} was never queued for analysis",
+                game_id
+            )))
+            .expect("Failed to build response for error message")
+    })?;
+
+    let attempts = sqlx::query_as!(
+        APIAnalysisAttempt,
+        r#"
+        SELECT
+            attempt_id,
+            started_unix_sec,
+            finished_unix_sec,
+            worker_id,
+            analysis_engine_version,
+            outcome AS "outcome: AnalysisQueueStatus",
+            error
+        FROM analysis_attempts
+        WHERE game_id = $1
+        ORDER BY attempt_id ASC
+        "#,
+        game_id
+    )
+    .fetch_all(&database)
+    .await
+    .map_err(into_error_resp)?;
+
+    let mut job = APIAnalysisJob {
+        game_id,
+        status: job.status,
+        requested_unix_sec: job.requested_unix_sec,
+        started_unix_sec: job.started_unix_sec,
+        priority: job.priority,
+        position: job.position,
+        worker_id: job.worker_id,
+        lease_expires_unix_sec: job.lease_expires_unix_sec,
+        attempts,
+    };
+    if !user.is_some_and(|u| u.has_permission(Permission::Admin)) {
+        job.worker_id = None;
+        for attempt in &mut job.attempts {
+            attempt.worker_id = None;
+            attempt.error = None;
+        }
+    }
+
+    Ok(Json(job))
+}
+
 async fn analysis_queue_handler(
     Extension(database): Extension<PgPool>,
 ) -> Result<Json<Vec<APIAnalysisQueueEntry>>, Response> {
@@ -379,7 +717,7 @@ pub struct SingleRecentGameEntry {
 
 async fn get_users_handler(
     Extension(database): Extension<PgPool>,
-    Extension(cfg): Extension<Arc<crate::Config>>,
+    Extension(ofclient): Extension<Arc<OpenFrontClient>>,
     //_user: APIUser,
     Path(user_id): Path<String>,
 ) -> Result<Json<SingleUserResponse>, Response> {
@@ -441,15 +779,16 @@ async fn get_users_handler(
     // Fetch overlay player data games
     if let Some(ref ofpid) = user.openfront_player_id {
         info!("Fetching OpenFront player data for user: {}", ofpid);
-        user_res.openfront_player_data = Some(cfg.get_player_data(&ofpid).await.map_err(|e| {
-            axum::response::Response::builder()
-                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(axum::body::Body::from(format!(
-                    "Failed to fetch OpenFront player data: {}",
-                    e
-                )))
-                .expect("Failed to build response for error message")
-        })?);
+        user_res.openfront_player_data =
+            Some(ofclient.get_player_data(&ofpid).await.map_err(|e| {
+                axum::response::Response::builder()
+                    .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(axum::body::Body::from(format!(
+                        "Failed to fetch OpenFront player data: {}",
+                        e
+                    )))
+                    .expect("Failed to build response for error message")
+            })?);
 
         //Fetch recent games
         user_res.recent_games = sqlx::query_as!(
@@ -544,15 +883,26 @@ pub fn routes(_openapi: OpenApi, cors: CorsLayer) -> ApiRouter {
     let api_routes = ApiRouter::new()
         .route("/lobbies", get(lobbies_handler).post(new_lobbies_handler))
         .route("/lobbies/{id}", get(lobbies_id_handler))
+        .route("/lobbies/{id}/history", get(lobbies_id_history_handler))
         .route("/analysis_queue", get(analysis_queue_handler))
+        .route("/analysis_queue/{game_id}", get(analysis_queue_job_handler))
         .route("/users", get(all_users_handler))
         .route("/users/{user_id}", get(get_users_handler))
         .route("/games/{game_id}", get(game_handler))
+        .route(
+            "/games/import",
+            post(game_import_handler).layer(axum::extract::DefaultBodyLimit::max(
+                import::MAX_UPLOAD_BYTES,
+            )),
+        )
         .route(
             "/games/{game_id}/analyze",
             post(game_analyze_handler).delete(game_analyze_handler_delete),
         )
-        .nest("/analysis/", analysis::api::analysis_api_router());
+        .nest("/analysis/", analysis::api::analysis_api_router())
+        .nest("/admin/", admin::admin_api_router())
+        .nest("/worker/", workers::workers_api_router())
+        .merge(sessions::sessions_api_router());
 
     ApiRouter::new()
         .route("/health", get(|| async { "ok!" }))
@@ -562,3 +912,383 @@ pub fn routes(_openapi: OpenApi, cors: CorsLayer) -> ApiRouter {
         .route("/redoc", Redoc::new("/openapi.json").axum_route())
         .layer(cors)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::openfrontapi::Lobby;
+
+    fn lobby(num_clients: i32, ms_until_start: u64) -> Lobby {
+        serde_json::from_value(serde_json::json!({
+            "gameID": "lobby001",
+            "numClients": num_clients,
+            "msUntilStart": ms_until_start,
+            "gameConfig": {
+                "gameMap": "World",
+                "gameType": "Public",
+                "difficulty": "Medium",
+                "disableNPCs": false,
+                "infiniteGold": false,
+                "infiniteTroops": false,
+                "instantBuild": false,
+                "gameMode": "Free For All",
+                "bots": 400,
+                "disabledUnits": [],
+                "maxPlayers": 50
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lobby_history() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+
+        tasks::insert_new_game(&lobby(5, 39_000), &db.pool)
+            .await
+            .unwrap();
+        // It was seen once earlier on too
+        sqlx::query(
+            "INSERT INTO lobby_snapshots (game_id, seen_unix_sec, num_clients, ms_until_start)
+            VALUES ('lobby001', 1000, 1, 60000)",
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        let Json(history) =
+            lobbies_id_history_handler(Extension(db.pool.clone()), Path("lobby001".into()))
+                .await
+                .unwrap();
+        assert_eq!(history.game_map, "World");
+        assert_eq!(history.max_players, 50);
+        let fill: Vec<_> = history
+            .snapshots
+            .iter()
+            .map(|s| (s.num_clients, s.ms_until_start))
+            .collect();
+        assert_eq!(fill, vec![(1, 60_000), (5, 39_000)]);
+
+        let missing =
+            lobbies_id_history_handler(Extension(db.pool.clone()), Path("nolobby1".into()))
+                .await
+                .unwrap_err();
+        assert_eq!(missing.status(), axum::http::StatusCode::NOT_FOUND);
+    }
+
+    fn user(user_id: &str) -> APIUser {
+        APIUser {
+            user_id: user_id.to_string(),
+            username: user_id.to_string(),
+            permissions: vec![],
+            session_id: None,
+        }
+    }
+
+    async fn analyze(
+        db: &PgPool,
+        game_id: &str,
+        user_id: &str,
+        force: bool,
+    ) -> Result<Json<APIAnalysisRequest>, Response> {
+        game_analyze_handler(
+            Extension(db.clone()),
+            Extension(Settings::new(db.clone())),
+            Path(game_id.to_string()),
+            Query(GameAnalyzeParams { force }),
+            user(user_id),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_analysis_queue_order() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        sqlx::raw_sql(
+            "INSERT INTO social.registered_users (id, username)
+            VALUES ('user1', 'User One'), ('user2', 'User Two');
+            INSERT INTO analysis_queue
+                (game_id, requesting_user_id, requested_unix_sec, started_unix_sec, status, priority)
+            VALUES
+                ('GAMEA001', 'user1', 100, NULL, 'Pending', 0),
+                ('GAMEB001', 'user1', 101, NULL, 'Pending', 0),
+                ('GAMEC001', 'user1', 102, NULL, 'Pending', 0),
+                ('GAMED002', 'user2', 103, NULL, 'Pending', 0),
+                ('GAMEE002', 'user2', 104, NULL, 'Pending', 0),
+                ('GAMEF000', NULL, 99, NULL, 'Pending', 0),
+                ('GAMEG002', 'user2', 105, NULL, 'Pending', 100),
+                ('GAMEH002', 'user2', 10, 50, 'Completed', 0);",
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        let order = sqlx::query_scalar!(
+            r#"SELECT game_id AS "game_id!" FROM analysis_queue_order ORDER BY position"#
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        // Priority first. Then everyone's first job, with the users who were never served before
+        // user2, then everyone's second job, and so on.
+        assert_eq!(
+            order,
+            vec![
+                "GAMEG002", "GAMEF000", "GAMEA001", "GAMED002", "GAMEB001", "GAMEE002", "GAMEC001"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pending_analysis_limit() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        sqlx::query(
+            "INSERT INTO social.registered_users (id, username) VALUES ('user1', 'User One')",
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        let limit = Settings::new(db.pool.clone())
+            .get()
+            .max_pending_analyses_per_user;
+
+        // Asking for more games at once than the limit allows only queues up to the limit
+        let requests = (0..limit + 5).map(|i| {
+            let db = db.pool.clone();
+            tokio::spawn(
+                async move { analyze(&db, &format!("GAME{:04}", i), "user1", false).await },
+            )
+        });
+        let mut queued = 0;
+        for res in futures::future::join_all(requests).await {
+            match res.unwrap() {
+                Ok(job) => {
+                    assert!(job.queued);
+                    queued += 1;
+                }
+                Err(res) => {
+                    assert_eq!(res.status(), axum::http::StatusCode::TOO_MANY_REQUESTS)
+                }
+            }
+        }
+        assert_eq!(queued, limit);
+    }
+
+    /// Every job the game had, the active one last
+    async fn job_statuses(db: &PgPool, game_id: &str) -> Vec<AnalysisQueueStatus> {
+        sqlx::query_scalar!(
+            r#"SELECT status AS "status: AnalysisQueueStatus" FROM analysis_queue
+            WHERE game_id = $1
+            ORDER BY status IN ('Pending', 'Running'), requested_unix_sec"#,
+            game_id
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_analyze_twice() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        sqlx::raw_sql(
+            "INSERT INTO social.registered_users (id, username)
+            VALUES ('user1', 'User One'), ('user2', 'User Two');",
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let game = crate::game_record::FinishedGame::from_value(json).unwrap();
+        let game_id = game.record.info.game_id.clone();
+        import::import_record(&db.pool, &game).await.unwrap();
+
+        let Json(job) = analyze(&db.pool, &game_id, "user1", false).await.unwrap();
+        assert!(job.queued);
+        assert_eq!(job.status, AnalysisQueueStatus::Pending);
+        assert_eq!(job.position, Some(1));
+
+        // Everyone asking while it is queued gets the same job
+        let Json(again) = analyze(&db.pool, &game_id, "user2", false).await.unwrap();
+        assert!(!again.queued);
+        assert_eq!(again.status, AnalysisQueueStatus::Pending);
+        assert!(
+            !import::enqueue_analysis(&db.pool, &game_id, None, 0)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            job_statuses(&db.pool, &game_id).await,
+            vec![AnalysisQueueStatus::Pending]
+        );
+
+        // Only the user who asked can cancel it
+        game_analyze_handler_delete(
+            Extension(db.pool.clone()),
+            Path(game_id.clone()),
+            user("user2"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            job_statuses(&db.pool, &game_id).await,
+            vec![AnalysisQueueStatus::Pending]
+        );
+
+        sqlx::query!(
+            "UPDATE analysis_queue SET status = 'Completed' WHERE game_id = $1",
+            game_id
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO analysis_1.completed_analysis (game_id, analysis_engine_version)
+            VALUES ($1, 'v1')",
+            game_id
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        // A finished job can't be cancelled
+        game_analyze_handler_delete(
+            Extension(db.pool.clone()),
+            Path(game_id.clone()),
+            user("user1"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            job_statuses(&db.pool, &game_id).await,
+            vec![AnalysisQueueStatus::Completed]
+        );
+
+        // Analyzed games are only queued again when forced
+        let Json(done) = analyze(&db.pool, &game_id, "user2", false).await.unwrap();
+        assert!(!done.queued);
+        assert_eq!(done.status, AnalysisQueueStatus::CompletedAlready);
+        assert!(
+            !import::enqueue_analysis(&db.pool, &game_id, None, 0)
+                .await
+                .unwrap()
+        );
+
+        let Json(forced) = analyze(&db.pool, &game_id, "user2", true).await.unwrap();
+        assert!(forced.queued);
+        assert_eq!(forced.status, AnalysisQueueStatus::Pending);
+        assert_eq!(
+            job_statuses(&db.pool, &game_id).await,
+            vec![AnalysisQueueStatus::Completed, AnalysisQueueStatus::Pending]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_analysis_job_attempts() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let game = crate::game_record::FinishedGame::from_value(json).unwrap();
+        let game_id = game.record.info.game_id.clone();
+        import::import_record(&db.pool, &game).await.unwrap();
+        import::enqueue_analysis(&db.pool, &game_id, None, 0)
+            .await
+            .unwrap();
+
+        // Alice's lease runs out, then Bob analyzes it
+        let set_status = |status: &'static str, worker_id: Option<&'static str>| {
+            sqlx::query(
+                "UPDATE analysis_queue SET status = $2::analysis_queue_status, worker_id = $3
+                WHERE game_id = $1",
+            )
+            .bind(game_id.clone())
+            .bind(status)
+            .bind(worker_id)
+            .execute(&db.pool)
+        };
+        set_status("Running", Some("alice")).await.unwrap();
+        set_status("Pending", None).await.unwrap();
+        set_status("Running", Some("bob")).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO analysis_1.completed_analysis (game_id, analysis_engine_version)
+            VALUES ($1, 'v2')",
+            game_id
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        set_status("Completed", Some("bob")).await.unwrap();
+
+        let job_for = |user: Option<APIUser>| {
+            analysis_queue_job_handler(Extension(db.pool.clone()), Path(game_id.clone()), user)
+        };
+        let mut admin = user("admin1");
+        admin.permissions = vec![Permission::Admin.as_str().to_string()];
+        let Json(job) = job_for(Some(admin)).await.unwrap();
+        assert_eq!(job.status, AnalysisQueueStatus::Completed);
+        assert_eq!(job.worker_id.as_deref(), Some("bob"));
+        let attempts: Vec<_> = job
+            .attempts
+            .iter()
+            .map(|a| {
+                (
+                    a.worker_id.as_deref(),
+                    a.outcome.clone(),
+                    a.error.as_deref(),
+                    a.analysis_engine_version.as_deref(),
+                    a.finished_unix_sec.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            attempts,
+            vec![
+                (
+                    Some("alice"),
+                    Some(AnalysisQueueStatus::Stalled),
+                    Some("Worker lease expired"),
+                    None,
+                    true
+                ),
+                (
+                    Some("bob"),
+                    Some(AnalysisQueueStatus::Completed),
+                    None,
+                    Some("v2"),
+                    true
+                ),
+            ]
+        );
+
+        // Everyone else sees the attempts, but not who ran them or why they failed
+        for user in [None, Some(user("user1"))] {
+            let Json(public) = job_for(user).await.unwrap();
+            assert_eq!(public.worker_id, None);
+            assert_eq!(public.attempts.len(), 2);
+            assert!(
+                public
+                    .attempts
+                    .iter()
+                    .all(|a| a.worker_id.is_none() && a.error.is_none())
+            );
+            assert_eq!(
+                public.attempts[0].outcome,
+                Some(AnalysisQueueStatus::Stalled)
+            );
+        }
+
+        let missing =
+            analysis_queue_job_handler(Extension(db.pool.clone()), Path("nogame01".into()), None)
+                .await
+                .unwrap_err();
+        assert_eq!(missing.status(), axum::http::StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,461 @@
+//! Endpoints for running the site. Every handler here needs [`Permission::Admin`].
+
+use aide::axum::ApiRouter;
+use axum::{
+    Extension, Json,
+    extract::Path,
+    response::Response,
+    routing::{get, post, put},
+};
+use schemars::JsonSchema;
+use sqlx::PgPool;
+
+use crate::{
+    ActiveTasks,
+    oauth::{APIUser, Permission},
+    settings::{self, SettingDef, SettingValue, Settings},
+    tasks::{
+        auto_analysis::{self, AnalysisRule, Decision, GameFacts, NewAnalysisRule},
+        registry::{TaskHandle, TaskRegistry, TaskStatus},
+    },
+};
+
+/// Status of every background task
+async fn tasks_handler(
+    Extension(registry): Extension<TaskRegistry>,
+    user: APIUser,
+) -> Result<Json<Vec<TaskStatus>>, Response> {
+    user.require(Permission::Admin)?;
+
+    Ok(Json(registry.snapshot()))
+}
+
+fn find_task(registry: &TaskRegistry, task: &ActiveTasks) -> Result<TaskHandle, Response> {
+    registry.find(task).ok_or_else(|| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from(format!(
+                "Task {:?} is not running on this server",
+                task
+            )))
+            .expect("Failed to build response for error message")
+    })
+}
+
+/// Stop a task from starting new runs until it is resumed
+async fn task_pause_handler(
+    Extension(registry): Extension<TaskRegistry>,
+    Path(task): Path<ActiveTasks>,
+    user: APIUser,
+) -> Result<Json<TaskStatus>, Response> {
+    user.require(Permission::Admin)?;
+
+    let handle = find_task(&registry, &task)?;
+    tracing::warn!(user.user_id, "{} is pausing {:?}", user.username, task);
+    handle.pause();
+
+    Ok(Json(handle.status()))
+}
+
+async fn task_resume_handler(
+    Extension(registry): Extension<TaskRegistry>,
+    Path(task): Path<ActiveTasks>,
+    user: APIUser,
+) -> Result<Json<TaskStatus>, Response> {
+    user.require(Permission::Admin)?;
+
+    let handle = find_task(&registry, &task)?;
+    tracing::warn!(user.user_id, "{} is resuming {:?}", user.username, task);
+    handle.resume();
+
+    Ok(Json(handle.status()))
+}
+
+/// Run a task as soon as possible, even if it is paused
+async fn task_run_handler(
+    Extension(registry): Extension<TaskRegistry>,
+    Path(task): Path<ActiveTasks>,
+    user: APIUser,
+) -> Result<Json<TaskStatus>, Response> {
+    user.require(Permission::Admin)?;
+
+    let handle = find_task(&registry, &task)?;
+    tracing::warn!(user.user_id, "{} is running {:?}", user.username, task);
+    handle.run_now();
+
+    Ok(Json(handle.status()))
+}
+
+fn into_error_resp(e: impl std::fmt::Display) -> Response {
+    axum::response::Response::builder()
+        .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        .body(axum::body::Body::from(format!("Error: {}", e)))
+        .expect("Failed to build response for error message")
+}
+
+fn rule_not_found(rule_id: i32) -> Response {
+    axum::response::Response::builder()
+        .status(axum::http::StatusCode::NOT_FOUND)
+        .body(axum::body::Body::from(format!(
+            "No analysis rule {}",
+            rule_id
+        )))
+        .expect("Failed to build response for error message")
+}
+
+/// Every auto analysis rule, in the order they are checked
+async fn analysis_rules_handler(
+    Extension(database): Extension<PgPool>,
+    user: APIUser,
+) -> Result<Json<Vec<AnalysisRule>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let rules = auto_analysis::load_rules(&database)
+        .await
+        .map_err(into_error_resp)?;
+
+    Ok(Json(rules))
+}
+
+async fn analysis_rule_create_handler(
+    Extension(database): Extension<PgPool>,
+    user: APIUser,
+    Json(rule): Json<NewAnalysisRule>,
+) -> Result<Json<Vec<AnalysisRule>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let rule_id = auto_analysis::insert_rule(&database, &rule)
+        .await
+        .map_err(into_error_resp)?;
+    tracing::warn!(
+        user.user_id,
+        rule_id,
+        "{} added analysis rule {:?}",
+        user.username,
+        rule
+    );
+
+    analysis_rules_handler(Extension(database), user).await
+}
+
+async fn analysis_rule_update_handler(
+    Extension(database): Extension<PgPool>,
+    Path(rule_id): Path<i32>,
+    user: APIUser,
+    Json(rule): Json<NewAnalysisRule>,
+) -> Result<Json<Vec<AnalysisRule>>, Response> {
+    user.require(Permission::Admin)?;
+
+    if !auto_analysis::update_rule(&database, rule_id, &rule)
+        .await
+        .map_err(into_error_resp)?
+    {
+        return Err(rule_not_found(rule_id));
+    }
+    tracing::warn!(
+        user.user_id,
+        rule_id,
+        "{} changed analysis rule to {:?}",
+        user.username,
+        rule
+    );
+
+    analysis_rules_handler(Extension(database), user).await
+}
+
+async fn analysis_rule_delete_handler(
+    Extension(database): Extension<PgPool>,
+    Path(rule_id): Path<i32>,
+    user: APIUser,
+) -> Result<Json<Vec<AnalysisRule>>, Response> {
+    user.require(Permission::Admin)?;
+
+    if !auto_analysis::delete_rule(&database, rule_id)
+        .await
+        .map_err(into_error_resp)?
+    {
+        return Err(rule_not_found(rule_id));
+    }
+    tracing::warn!(
+        user.user_id,
+        rule_id,
+        "{} deleted an analysis rule",
+        user.username
+    );
+
+    analysis_rules_handler(Extension(database), user).await
+}
+
+#[derive(Debug, Clone, serde::Deserialize, JsonSchema)]
+struct DryRunRequest {
+    /// Try these rules instead of the saved ones
+    rules: Option<Vec<NewAnalysisRule>>,
+    /// How many of the most recently finished games to check, 100 by default
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+struct DryRunResult {
+    game: GameFacts,
+    decision: Decision,
+}
+
+/// What the rules would do with recently finished games. Nothing is queued.
+async fn analysis_rules_dry_run_handler(
+    Extension(database): Extension<PgPool>,
+    user: APIUser,
+    Json(req): Json<DryRunRequest>,
+) -> Result<Json<Vec<DryRunResult>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let saved;
+    let rules: Vec<(Option<i32>, &NewAnalysisRule)> = match &req.rules {
+        Some(rules) => rules.iter().map(|r| (None, r)).collect(),
+        None => {
+            saved = auto_analysis::load_rules(&database)
+                .await
+                .map_err(into_error_resp)?;
+            saved.iter().map(|r| (Some(r.rule_id), &r.rule)).collect()
+        }
+    };
+
+    let games = auto_analysis::game_facts(&database, None, req.limit.unwrap_or(100).clamp(1, 1000))
+        .await
+        .map_err(into_error_resp)?;
+
+    Ok(Json(
+        games
+            .into_iter()
+            .map(|game| DryRunResult {
+                decision: auto_analysis::decide(rules.iter().copied(), &game),
+                game,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+struct APISetting {
+    #[serde(flatten)]
+    def: SettingDef,
+    /// The value in use right now
+    value: serde_json::Value,
+    /// False if the value was changed from the default
+    is_default: bool,
+}
+
+fn find_setting_def(key: &str) -> Result<&'static SettingDef, Response> {
+    settings::find_setting(key).ok_or_else(|| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from(format!("Unknown setting {}", key)))
+            .expect("Failed to build response for error message")
+    })
+}
+
+/// Every runtime setting with its current value
+async fn settings_handler(
+    Extension(settings): Extension<Settings>,
+    user: APIUser,
+) -> Result<Json<Vec<APISetting>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let current = serde_json::to_value(&*settings.get()).map_err(into_error_resp)?;
+    let stored = settings.stored().await.map_err(into_error_resp)?;
+
+    Ok(Json(
+        settings::KNOWN_SETTINGS
+            .iter()
+            .map(|def| APISetting {
+                def: def.clone(),
+                value: current[def.key].clone(),
+                is_default: !stored.contains_key(def.key),
+            })
+            .collect(),
+    ))
+}
+
+/// Change a runtime setting. Every server picks up the new value within a few seconds.
+async fn setting_update_handler(
+    Extension(settings): Extension<Settings>,
+    Path(key): Path<String>,
+    user: APIUser,
+    Json(value): Json<SettingValue>,
+) -> Result<Json<Vec<APISetting>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let def = find_setting_def(&key)?;
+    let raw = def.validate(&value).map_err(|e| {
+        axum::response::Response::builder()
+            .status(axum::http::StatusCode::BAD_REQUEST)
+            .body(axum::body::Body::from(e))
+            .expect("Failed to build response for error message")
+    })?;
+
+    settings.set(def, &raw).await.map_err(into_error_resp)?;
+    tracing::warn!(user.user_id, "{} set {} to {}", user.username, key, raw);
+
+    settings_handler(Extension(settings), user).await
+}
+
+/// Put a runtime setting back to its default
+async fn setting_reset_handler(
+    Extension(settings): Extension<Settings>,
+    Path(key): Path<String>,
+    user: APIUser,
+) -> Result<Json<Vec<APISetting>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let def = find_setting_def(&key)?;
+    settings.reset(def).await.map_err(into_error_resp)?;
+    tracing::warn!(
+        user.user_id,
+        "{} reset {} to its default",
+        user.username,
+        key
+    );
+
+    settings_handler(Extension(settings), user).await
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+struct APIWorker {
+    worker_id: String,
+    created_at_unix_sec: i64,
+    last_seen_unix_sec: Option<i64>,
+    /// Jobs this worker holds a lease on right now
+    running_jobs: i64,
+}
+
+/// Analysis workers that can claim jobs
+async fn workers_handler(
+    Extension(database): Extension<PgPool>,
+    user: APIUser,
+) -> Result<Json<Vec<APIWorker>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let workers = sqlx::query_as!(
+        APIWorker,
+        r#"
+        SELECT
+            w.worker_id,
+            w.created_at_unix_sec,
+            w.last_seen_unix_sec,
+            (
+                SELECT COUNT(*) FROM analysis_queue aq
+                WHERE aq.worker_id = w.worker_id AND aq.status = 'Running'
+            ) AS "running_jobs!"
+        FROM analysis_workers w
+        ORDER BY w.worker_id
+        "#
+    )
+    .fetch_all(&database)
+    .await
+    .map_err(into_error_resp)?;
+
+    Ok(Json(workers))
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+struct WorkerToken {
+    worker_id: String,
+    /// Only shown this once, we keep just a hash of it
+    token: String,
+}
+
+/// Create a worker, or give an existing one a new token. Its old token stops working.
+async fn worker_token_handler(
+    Extension(database): Extension<PgPool>,
+    Path(worker_id): Path<String>,
+    user: APIUser,
+) -> Result<Json<WorkerToken>, Response> {
+    user.require(Permission::Admin)?;
+
+    let token = sqlx::query_scalar!(
+        r#"
+        WITH new_token AS (
+            SELECT encode(gen_random_bytes(24), 'hex') AS token
+        )
+        INSERT INTO analysis_workers (worker_id, token_hash)
+        VALUES ($1, encode(digest((SELECT token FROM new_token), 'sha256'), 'hex'))
+        ON CONFLICT (worker_id) DO UPDATE SET token_hash = EXCLUDED.token_hash
+        RETURNING (SELECT token FROM new_token) AS "token!"
+        "#,
+        worker_id
+    )
+    .fetch_one(&database)
+    .await
+    .map_err(into_error_resp)?;
+    tracing::warn!(
+        user.user_id,
+        worker_id,
+        "{} issued a worker token",
+        user.username
+    );
+
+    Ok(Json(WorkerToken { worker_id, token }))
+}
+
+/// Revoke a worker's token. Jobs it holds go back to Pending once their leases expire.
+async fn worker_delete_handler(
+    Extension(database): Extension<PgPool>,
+    Path(worker_id): Path<String>,
+    user: APIUser,
+) -> Result<Json<Vec<APIWorker>>, Response> {
+    user.require(Permission::Admin)?;
+
+    let res = sqlx::query!(
+        "DELETE FROM analysis_workers WHERE worker_id = $1",
+        worker_id
+    )
+    .execute(&database)
+    .await
+    .map_err(into_error_resp)?;
+    if res.rows_affected() == 0 {
+        return Err(axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from(format!(
+                "Worker {} not found",
+                worker_id
+            )))
+            .expect("Failed to build response for error message"));
+    }
+    tracing::warn!(
+        user.user_id,
+        worker_id,
+        "{} deleted a worker",
+        user.username
+    );
+
+    workers_handler(Extension(database), user).await
+}
+
+pub fn admin_api_router() -> ApiRouter {
+    ApiRouter::new()
+        .route("/tasks", get(tasks_handler))
+        .route("/tasks/{task}/pause", post(task_pause_handler))
+        .route("/tasks/{task}/resume", post(task_resume_handler))
+        .route("/tasks/{task}/run", post(task_run_handler))
+        .route(
+            "/analysis_rules",
+            get(analysis_rules_handler).post(analysis_rule_create_handler),
+        )
+        .route(
+            "/analysis_rules/{rule_id}",
+            put(analysis_rule_update_handler).delete(analysis_rule_delete_handler),
+        )
+        .route(
+            "/analysis_rules/dry_run",
+            post(analysis_rules_dry_run_handler),
+        )
+        .route("/settings", get(settings_handler))
+        .route(
+            "/settings/{key}",
+            put(setting_update_handler).delete(setting_reset_handler),
+        )
+        .route("/workers", get(workers_handler))
+        .route(
+            "/workers/{worker_id}",
+            post(worker_token_handler).delete(worker_delete_handler),
+        )
+}
@@ -1,13 +1,16 @@
+use reqwest::{
+    StatusCode,
+    header::{HeaderMap, HeaderValue},
+};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
 
 use crate::{Config, database::GameConfig};
-use anyhow::Result;
-
-use crate::utils::ReqwestErrorHandlingExtension;
 
 /// Response from openfront.io/public/lobbies
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -26,6 +29,71 @@ pub struct Lobby {
     pub ms_until_start: u64,
 }
 
+type Result<T> = std::result::Result<T, OpenFrontError>;
+
+/// Everything that can go wrong when talking to the OpenFront upstream
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum OpenFrontError {
+    #[error("Not found")]
+    NotFound,
+    #[error("Rate limited by OpenFront (retry after {retry_after:?})")]
+    RateLimited { retry_after: Option<Duration> },
+    /// Cloudflare sent a "Just a moment..." HTML page instead of JSON
+    #[error("OpenFront returned a Cloudflare challenge page")]
+    ChallengePage,
+    /// `retry_after` is only set for a 503 that said when to come back
+    #[error("OpenFront returned status {status}")]
+    Upstream5xx {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    #[error("OpenFront returned an invalid body: {0}")]
+    MalformedBody(String),
+    #[error("Request to OpenFront timed out")]
+    Timeout,
+    #[error("Request to OpenFront failed: {0}")]
+    Network(String),
+    #[error("OpenFront returned status {status}: {body}")]
+    UnexpectedStatus { status: u16, body: String },
+}
+
+impl OpenFrontError {
+    /// These errors are about the upstream, not about what we asked for, so asking again later
+    /// should work. A garbled body is usually a response that was cut off.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            OpenFrontError::RateLimited { .. }
+                | OpenFrontError::ChallengePage
+                | OpenFrontError::Upstream5xx { .. }
+                | OpenFrontError::MalformedBody(_)
+                | OpenFrontError::Timeout
+                | OpenFrontError::Network(_)
+        )
+    }
+
+    /// How long the upstream asked us to wait, from `Retry-After` on a 429 or 503
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            OpenFrontError::RateLimited { retry_after }
+            | OpenFrontError::Upstream5xx { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for OpenFrontError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            OpenFrontError::Timeout
+        } else if e.is_decode() {
+            OpenFrontError::MalformedBody(e.to_string())
+        } else {
+            OpenFrontError::Network(e.to_string())
+        }
+    }
+}
+
 #[mockall::automock]
 /// Trait for OpenFront API interactions
 pub trait OpenFrontAPI {
@@ -34,40 +102,246 @@ pub trait OpenFrontAPI {
     fn get_lobbies(&self) -> impl Future<Output = Result<PublicLobbiesResponse>> + Send;
 }
 
-impl OpenFrontAPI for Config {
+/// Shared HTTP client for every request to the OpenFront upstream.
+///
+/// All requests share one connection pool and one global rate limit. Requests that fail with a
+/// timeout, 429 or 5xx are retried with exponential backoff, honouring `Retry-After` on a 429 or
+/// 503.
+#[derive(Debug)]
+pub struct OpenFrontClient {
+    client: reqwest::Client,
+    lobby_url: String,
+    api_url: String,
+    limiter: RateLimiter,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+/// Never wait longer than this between two retries, even if the upstream asks us to
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(5 * 60);
+
+impl OpenFrontClient {
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Some(ref useragent) = cfg.useragent {
+            headers.insert(
+                reqwest::header::USER_AGENT,
+                HeaderValue::from_str(useragent)?,
+            );
+        }
+        if let Some(ref cookie) = cfg.cookie {
+            headers.insert(reqwest::header::COOKIE, HeaderValue::from_str(cookie)?);
+        }
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(cfg.openfront_timeout_secs))
+            .connect_timeout(Duration::from_secs(cfg.openfront_timeout_secs))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()?;
+
+        Ok(OpenFrontClient {
+            client,
+            lobby_url: cfg.openfront_lobby_url.clone(),
+            api_url: cfg.openfront_api_url.clone(),
+            limiter: RateLimiter::new(cfg.openfront_requests_per_sec),
+            max_retries: cfg.openfront_max_retries,
+            retry_backoff: Duration::from_millis(cfg.openfront_retry_backoff_ms),
+        })
+    }
+
+    /// GET a url from the upstream and return the body, waiting for the rate limit and retrying
+    /// transient failures
+    async fn get(&self, url: &str) -> Result<String> {
+        let mut attempt = 0;
+        loop {
+            self.limiter.acquire().await;
+            let err = match self.get_once(url).await {
+                Ok(body) => return Ok(body),
+                Err(e) => e,
+            };
+
+            // Asking again right away won't get us past a challenge page
+            if !err.is_transient() || err == OpenFrontError::ChallengePage {
+                return Err(err);
+            }
+            if attempt >= self.max_retries {
+                return Err(err);
+            }
+
+            let retry_after = err.retry_after();
+            let wait = retry_after
+                .unwrap_or_else(|| backoff_for_attempt(self.retry_backoff, attempt))
+                .min(MAX_RETRY_WAIT);
+            tracing::warn!(
+                url,
+                attempt,
+                wait_ms = wait.as_millis() as u64,
+                "OpenFront request failed ({}), retrying",
+                err
+            );
+
+            if retry_after.is_some() {
+                // The upstream told us to slow down, so every other request has to wait too.
+                self.limiter.pause_for(wait).await;
+            } else {
+                tokio::time::sleep(wait).await;
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn get_once(&self, url: &str) -> Result<String> {
+        let res = self.client.get(url).send().await?;
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| parse_retry_after(h, SystemTime::now()));
+        let challenge = res
+            .headers()
+            .get("cf-mitigated")
+            .is_some_and(|h| h == "challenge");
+        let body = res.text().await?;
+
+        if challenge {
+            return Err(OpenFrontError::ChallengePage);
+        }
+
+        classify_response(status, retry_after, body)
+    }
+}
+
+impl OpenFrontAPI for OpenFrontClient {
     async fn get_game_json(&self, game_id: &str) -> Result<Value> {
-        let url = format!("{}/game/{}", self.openfront_api_url, game_id);
+        let url = format!("{}/game/{}", self.api_url, game_id);
 
-        let finished = reqwest::get(url).await?.json::<Value>().await?;
+        let finished = parse_json(&self.get(&url).await?)?;
 
         Ok(finished)
     }
 
     async fn get_player_data(&self, player_id: &str) -> Result<Value> {
-        let url = format!("{}/player/{}", self.openfront_api_url, player_id);
+        let url = format!("{}/player/{}", self.api_url, player_id);
 
-        let player_data = reqwest::get(url).await?.json::<Value>().await?;
+        let player_data = parse_json(&self.get(&url).await?)?;
 
         Ok(player_data)
     }
 
     async fn get_lobbies(&self) -> Result<PublicLobbiesResponse> {
-        let mut base = reqwest::Client::new().get(&self.openfront_lobby_url);
-        if let Some(ref useragent) = self.useragent {
-            base = base.header(reqwest::header::USER_AGENT, useragent);
+        let new_games = parse_json(&self.get(&self.lobby_url).await?)?;
+
+        Ok(new_games)
+    }
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(body: &str) -> Result<T> {
+    serde_json::from_str(body).map_err(|e| OpenFrontError::MalformedBody(e.to_string()))
+}
+
+/// Turns an upstream response into either its body or the matching [`OpenFrontError`]
+fn classify_response(
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: String,
+) -> Result<String> {
+    if is_challenge_page(&body) {
+        return Err(OpenFrontError::ChallengePage);
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(OpenFrontError::RateLimited { retry_after });
+    }
+
+    if status.is_server_error() {
+        return Err(OpenFrontError::Upstream5xx {
+            status: status.as_u16(),
+            retry_after: retry_after.filter(|_| status == StatusCode::SERVICE_UNAVAILABLE),
+        });
+    }
+
+    if status == StatusCode::NOT_FOUND {
+        return Err(OpenFrontError::NotFound);
+    }
+
+    if !status.is_success() {
+        return Err(OpenFrontError::UnexpectedStatus {
+            status: status.as_u16(),
+            body: body.chars().take(500).collect(),
+        });
+    }
+
+    Ok(body)
+}
+
+/// Cloudflare sometimes answers with an HTML "Just a moment..." page instead of our JSON
+fn is_challenge_page(body: &str) -> bool {
+    let start = body.trim_start();
+    (start.starts_with("<!DOCTYPE html") || start.starts_with("<html"))
+        && (body.contains("<title>Just a moment...</title>") || body.contains("challenge-platform"))
+}
+
+/// Spaces requests out evenly so we never send more than `requests_per_sec` across all tasks
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// A rate of 0 disables the limit
+    fn new(requests_per_sec: f64) -> Self {
+        let interval = if requests_per_sec > 0.0 {
+            Duration::from_secs_f64(1.0 / requests_per_sec)
+        } else {
+            Duration::ZERO
+        };
+
+        RateLimiter {
+            interval,
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
         }
-        if let Some(ref cookie) = self.cookie {
-            base = base.header(reqwest::header::COOKIE, cookie);
+    }
+
+    /// Wait until we are allowed to send the next request
+    async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// Hold back every request for at least `wait`
+    async fn pause_for(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        {
+            let mut next_slot = self.next_slot.lock().await;
+            *next_slot = (*next_slot).max(until);
         }
 
-        let new_games = base
-            .send()
-            .await?
-            .anyhow_error_json::<PublicLobbiesResponse>()
-            .await?;
+        tokio::time::sleep_until(until).await;
+    }
+}
 
-        Ok(new_games)
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
     }
+
+    let date = httpdate::parse_http_date(value.trim()).ok()?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Exponential backoff: `base`, `2 * base`, `4 * base`, ...
+fn backoff_for_attempt(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt))
 }
 
 impl<T> OpenFrontAPI for &T
@@ -103,3 +377,100 @@ where
         T::get_player_data(self, player_id)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        // Dates in the past mean retry now
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_classify_response() {
+        let ok = classify_response(StatusCode::OK, None, "{}".into());
+        assert_eq!(ok, Ok("{}".to_string()));
+
+        let challenge =
+            r#"<!DOCTYPE html><html lang="en-US"><head><title>Just a moment...</title>"#;
+        for status in [
+            StatusCode::OK,
+            StatusCode::FORBIDDEN,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ] {
+            assert_eq!(
+                classify_response(status, None, challenge.into()),
+                Err(OpenFrontError::ChallengePage)
+            );
+        }
+
+        assert_eq!(
+            classify_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(Duration::from_secs(3)),
+                "".into()
+            ),
+            Err(OpenFrontError::RateLimited {
+                retry_after: Some(Duration::from_secs(3))
+            })
+        );
+        assert_eq!(
+            classify_response(
+                StatusCode::BAD_GATEWAY,
+                Some(Duration::from_secs(3)),
+                "".into()
+            ),
+            Err(OpenFrontError::Upstream5xx {
+                status: 502,
+                retry_after: None
+            })
+        );
+        let unavailable = classify_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some(Duration::from_secs(3)),
+            "".into(),
+        )
+        .unwrap_err();
+        assert_eq!(unavailable.retry_after(), Some(Duration::from_secs(3)));
+        assert_eq!(
+            classify_response(
+                StatusCode::NOT_FOUND,
+                None,
+                r#"{"error":"Not found"}"#.into()
+            ),
+            Err(OpenFrontError::NotFound)
+        );
+        assert!(matches!(
+            classify_response(StatusCode::FORBIDDEN, None, "nope".into()),
+            Err(OpenFrontError::UnexpectedStatus { status: 403, .. })
+        ));
+        assert!(matches!(
+            parse_json::<Value>("<html>"),
+            Err(OpenFrontError::MalformedBody(_))
+        ));
+    }
+
+    #[test]
+    fn test_backoff_for_attempt() {
+        let base = Duration::from_millis(500);
+        assert_eq!(backoff_for_attempt(base, 0), Duration::from_millis(500));
+        assert_eq!(backoff_for_attempt(base, 1), Duration::from_secs(1));
+        assert_eq!(backoff_for_attempt(base, 3), Duration::from_secs(4));
+    }
+}
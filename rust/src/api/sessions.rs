@@ -0,0 +1,215 @@
+//! Endpoints for a user to manage their own logins
+
+use std::sync::Arc;
+
+use aide::axum::ApiRouter;
+use axum::{
+    Extension, Json,
+    extract::Path,
+    response::Response,
+    routing::{delete, get, post},
+};
+use schemars::JsonSchema;
+use sqlx::PgPool;
+
+use crate::{
+    Config,
+    oauth::{APIUser, cookie_attributes, session_token},
+};
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct APISession {
+    pub session_id: i32,
+    pub created_at_unix_sec: i64,
+    pub expires_at_unix_sec: i64,
+    /// This is the session making the request
+    pub current: bool,
+}
+
+fn into_error_resp(e: impl std::fmt::Display) -> Response {
+    axum::response::Response::builder()
+        .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        .body(axum::body::Body::from(format!("Error: {}", e)))
+        .expect("Failed to build response for error message")
+}
+
+/// Every session of the logged in user that hasn't expired
+async fn sessions_handler(
+    Extension(database): Extension<PgPool>,
+    user: APIUser,
+) -> Result<Json<Vec<APISession>>, Response> {
+    let sessions = sqlx::query!(
+        r#"
+        SELECT session_id, created_at_unix_sec, expires_at_unix_sec
+        FROM social.user_sessions
+        WHERE
+            user_id = $1
+            AND expires_at_unix_sec > EXTRACT(EPOCH FROM NOW())
+        ORDER BY created_at_unix_sec DESC
+        "#,
+        user.user_id
+    )
+    .fetch_all(&database)
+    .await
+    .map_err(into_error_resp)?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|s| APISession {
+                session_id: s.session_id,
+                created_at_unix_sec: s.created_at_unix_sec,
+                expires_at_unix_sec: s.expires_at_unix_sec,
+                current: Some(s.session_id) == user.session_id,
+            })
+            .collect(),
+    ))
+}
+
+/// Log out one of your sessions, such as one on another device
+async fn revoke_session_handler(
+    Extension(database): Extension<PgPool>,
+    Path(session_id): Path<i32>,
+    user: APIUser,
+) -> Result<(), Response> {
+    let res = sqlx::query!(
+        "DELETE FROM social.user_sessions WHERE session_id = $1 AND user_id = $2",
+        session_id,
+        user.user_id
+    )
+    .execute(&database)
+    .await
+    .map_err(into_error_resp)?;
+
+    if res.rows_affected() == 0 {
+        return Err(axum::response::Response::builder()
+            .status(axum::http::StatusCode::NOT_FOUND)
+            .body(axum::body::Body::from("Session not found"))
+            .expect("Failed to build response for error message"));
+    }
+
+    tracing::info!(user.user_id, session_id, "Session revoked");
+
+    Ok(())
+}
+
+/// Ends the current session and clears the login cookies. This works without a valid session,
+/// so a browser holding an expired one can still get rid of its cookies.
+async fn logout_handler(
+    Extension(database): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, Response> {
+    if let Some(token) = session_token(&headers) {
+        sqlx::query!(
+            "DELETE FROM social.user_sessions
+            WHERE session_token_hash = encode(digest($1, 'sha256'), 'hex')",
+            token
+        )
+        .execute(&database)
+        .await
+        .map_err(into_error_resp)?;
+    }
+
+    let attributes = config
+        .get_discord_oauth()
+        .map(|cfg| cookie_attributes(&cfg))
+        .unwrap_or("; SameSite=Lax");
+
+    let res = Response::builder()
+        .status(axum::http::StatusCode::OK)
+        .header(axum::http::header::CACHE_CONTROL, "no-cache")
+        .header(
+            axum::http::header::SET_COOKIE,
+            expired_cookie("discord_user_id", attributes),
+        )
+        .header(
+            axum::http::header::SET_COOKIE,
+            expired_cookie("session_token", attributes),
+        )
+        .body(axum::body::Body::from("Logged out"))
+        .map_err(into_error_resp)?;
+
+    Ok(res)
+}
+
+/// A Set-Cookie value that makes the browser delete the cookie
+fn expired_cookie(name: &str, attributes: &str) -> String {
+    format!(
+        "{}=; Path=/; HttpOnly; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT{}",
+        name, attributes
+    )
+}
+
+pub fn sessions_api_router() -> ApiRouter {
+    ApiRouter::new()
+        .route("/sessions", get(sessions_handler))
+        .route("/sessions/{session_id}", delete(revoke_session_handler))
+        .route("/logout", post(logout_handler))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expired_cookie() {
+        let cookie = expired_cookie("session_token", "; Secure; SameSite=Lax");
+        let parsed = axum_extra::extract::cookie::Cookie::parse(cookie).unwrap();
+        assert_eq!(parsed.name(), "session_token");
+        assert_eq!(parsed.value(), "");
+        assert_eq!(parsed.path(), Some("/"));
+        assert_eq!(parsed.max_age().map(|d| d.whole_seconds()), Some(0));
+        assert_eq!(parsed.secure(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_logout_with_expired_session() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let config = Arc::new(<Config as clap::Parser>::parse_from(["openfrontpro"]));
+
+        sqlx::raw_sql(
+            "INSERT INTO social.registered_users (id, username) VALUES ('user1', 'User One');
+            INSERT INTO social.user_sessions (user_id, session_token_hash, expires_at_unix_sec)
+            VALUES
+                ('user1', encode(digest('expired', 'sha256'), 'hex'), 1),
+                ('user1', encode(digest('other', 'sha256'), 'hex'), 1)",
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        let logout = |cookie: Option<&str>| {
+            let mut headers = axum::http::HeaderMap::new();
+            if let Some(cookie) = cookie {
+                headers.insert(axum::http::header::COOKIE, cookie.parse().unwrap());
+            }
+            logout_handler(
+                Extension(db.pool.clone()),
+                Extension(config.clone()),
+                headers,
+            )
+        };
+
+        for cookie in [Some("session_token=expired"), None] {
+            let res = logout(cookie).await.unwrap();
+            assert_eq!(res.status(), axum::http::StatusCode::OK);
+            let cleared: Vec<_> = res
+                .headers()
+                .get_all(axum::http::header::SET_COOKIE)
+                .iter()
+                .map(|c| c.to_str().unwrap().split('=').next().unwrap().to_string())
+                .collect();
+            assert_eq!(cleared, ["discord_user_id", "session_token"]);
+        }
+
+        // Only the session that logged out is gone
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM social.user_sessions")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 1);
+    }
+}
@@ -0,0 +1,509 @@
+//! Endpoints for analysis workers: claim a job, keep its lease alive, and report how it went.
+//!
+//! Workers authenticate with `Authorization: Bearer <token>`, using a token an admin created with
+//! `POST /api/v1/admin/workers/{worker_id}`. A claimed job is leased to the worker for
+//! `worker_lease_secs`. If the worker stops sending heartbeats, the lease expires and the job
+//! goes back to Pending for someone else.
+//!
+//! A worker runs a job by claiming it, uploading the analysis to `/jobs/{game_id}/analysis`, and
+//! then reporting the result.
+
+use std::sync::Arc;
+
+use aide::axum::ApiRouter;
+use axum::{
+    Extension, Json, RequestPartsExt,
+    extract::{FromRequestParts, Path},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use schemars::JsonSchema;
+use sqlx::PgPool;
+
+use crate::{
+    AnalysisQueueStatus, Config,
+    analysis::ingest::{self, IngestError},
+};
+
+/// A worker that sent a valid token
+#[derive(Debug, Clone)]
+pub struct AnalysisWorker {
+    pub worker_id: String,
+}
+
+fn error_resp(status: axum::http::StatusCode, msg: impl std::fmt::Display) -> Response {
+    axum::response::Response::builder()
+        .status(status)
+        .body(axum::body::Body::from(msg.to_string()))
+        .expect("Failed to build response for error message")
+}
+
+fn into_error_resp(e: impl std::fmt::Display) -> Response {
+    error_resp(
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error: {}", e),
+    )
+}
+
+impl<S: Sync> FromRequestParts<S> for AnalysisWorker {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = |msg: &str| error_resp(axum::http::StatusCode::UNAUTHORIZED, msg);
+
+        let token = parts
+            .headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|s| s.trim().to_string())
+            .ok_or_else(|| unauthorized("Missing worker token"))?;
+
+        let Extension(db) = parts
+            .extract::<Extension<PgPool>>()
+            .await
+            .map_err(|_| into_error_resp("Database not available"))?;
+
+        let worker_id = sqlx::query_scalar!(
+            "UPDATE analysis_workers
+            SET last_seen_unix_sec = EXTRACT(EPOCH FROM NOW())
+            WHERE token_hash = encode(digest($1, 'sha256'), 'hex')
+            RETURNING worker_id",
+            token
+        )
+        .fetch_optional(&db)
+        .await
+        .map_err(into_error_resp)?
+        .ok_or_else(|| unauthorized("Invalid worker token"))?;
+
+        Ok(AnalysisWorker { worker_id })
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct ClaimedJob {
+    pub game_id: String,
+    pub result_json: serde_json::Value,
+    /// Send a heartbeat before this, or the job is given to another worker
+    pub lease_expires_unix_sec: i64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct JobLease {
+    pub game_id: String,
+    pub lease_expires_unix_sec: i64,
+}
+
+/// 409 for a job this worker no longer holds
+fn lease_lost(game_id: &str) -> Response {
+    error_resp(
+        axum::http::StatusCode::CONFLICT,
+        format!("Job {} is not leased to this worker", game_id),
+    )
+}
+
+/// Claim the next pending job, in the order of `analysis_queue_order`. Returns 204 if there is
+/// nothing to do.
+async fn claim_handler(
+    Extension(database): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    worker: AnalysisWorker,
+) -> Result<Response, Response> {
+    let job = sqlx::query!(
+        r#"
+        WITH job AS (
+            SELECT aq.game_id
+            FROM analysis_queue aq
+                JOIN analysis_queue_order o ON o.game_id = aq.game_id
+                JOIN finished_games fg ON fg.game_id = aq.game_id
+            WHERE
+                aq.status = 'Pending'
+                AND fg.is_ok
+            ORDER BY o.position ASC
+            LIMIT 1
+            FOR UPDATE OF aq SKIP LOCKED
+        )
+        UPDATE analysis_queue aq
+        SET
+            status = 'Running',
+            started_unix_sec = EXTRACT(EPOCH FROM NOW()),
+            worker_id = $1,
+            lease_expires_unix_sec = EXTRACT(EPOCH FROM NOW()) + $2::BIGINT
+        FROM job
+        WHERE aq.game_id = job.game_id AND aq.status = 'Pending'
+        RETURNING aq.game_id, aq.lease_expires_unix_sec AS "lease_expires_unix_sec!"
+        "#,
+        worker.worker_id,
+        config.worker_lease_secs,
+    )
+    .fetch_optional(&database)
+    .await
+    .map_err(into_error_resp)?;
+
+    let Some(job) = job else {
+        return Ok(axum::http::StatusCode::NO_CONTENT.into_response());
+    };
+
+    let result_json = sqlx::query_scalar!(
+        "SELECT result_json FROM finished_games WHERE game_id = $1",
+        job.game_id
+    )
+    .fetch_one(&database)
+    .await
+    .map_err(into_error_resp)?
+    .unwrap_or_default();
+
+    tracing::info!(
+        worker.worker_id,
+        game_id = job.game_id,
+        "Worker claimed job"
+    );
+
+    Ok(Json(ClaimedJob {
+        game_id: job.game_id,
+        result_json,
+        lease_expires_unix_sec: job.lease_expires_unix_sec,
+    })
+    .into_response())
+}
+
+/// Extend the lease on a running job
+async fn heartbeat_handler(
+    Extension(database): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Path(game_id): Path<String>,
+    worker: AnalysisWorker,
+) -> Result<Json<JobLease>, Response> {
+    let lease = sqlx::query_scalar!(
+        r#"
+        UPDATE analysis_queue
+        SET lease_expires_unix_sec = EXTRACT(EPOCH FROM NOW()) + $3::BIGINT
+        WHERE
+            game_id = $1
+            AND worker_id = $2
+            AND status = 'Running'
+        RETURNING lease_expires_unix_sec AS "lease_expires_unix_sec!"
+        "#,
+        game_id,
+        worker.worker_id,
+        config.worker_lease_secs,
+    )
+    .fetch_optional(&database)
+    .await
+    .map_err(into_error_resp)?
+    .ok_or_else(|| lease_lost(&game_id))?;
+
+    Ok(Json(JobLease {
+        game_id,
+        lease_expires_unix_sec: lease,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub enum JobOutcome {
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct JobResult {
+    pub outcome: JobOutcome,
+    /// Why the job failed. Kept with the attempt, so the whole stack is welcome.
+    pub error: Option<String>,
+}
+
+/// Finish a running job
+async fn result_handler(
+    Extension(database): Extension<PgPool>,
+    Path(game_id): Path<String>,
+    worker: AnalysisWorker,
+    Json(result): Json<JobResult>,
+) -> Result<(), Response> {
+    let status = match result.outcome {
+        JobOutcome::Completed => AnalysisQueueStatus::Completed,
+        JobOutcome::Failed => AnalysisQueueStatus::Failed,
+    };
+
+    let mut txn = database.begin().await.map_err(into_error_resp)?;
+
+    // The attempt gets the engine version once this run's analysis is saved, see the
+    // analysis_attempts migration. An analysis left over from an earlier run doesn't count.
+    if result.outcome == JobOutcome::Completed {
+        let analyzed = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM analysis_attempts
+                WHERE
+                    game_id = $1
+                    AND worker_id = $2
+                    AND finished_unix_sec IS NULL
+                    AND analysis_engine_version IS NOT NULL
+            ) AS "exists!""#,
+            game_id,
+            worker.worker_id,
+        )
+        .fetch_one(&mut *txn)
+        .await
+        .map_err(into_error_resp)?;
+        if !analyzed {
+            return Err(error_resp(
+                axum::http::StatusCode::CONFLICT,
+                format!("Upload the analysis of {} before completing it", game_id),
+            ));
+        }
+    }
+
+    // This also closes the attempt, see the analysis_attempts migration
+    let res = sqlx::query!(
+        "UPDATE analysis_queue
+        SET status = $3, lease_expires_unix_sec = NULL
+        WHERE
+            game_id = $1
+            AND worker_id = $2
+            AND status = 'Running'",
+        game_id,
+        worker.worker_id,
+        status as AnalysisQueueStatus,
+    )
+    .execute(&mut *txn)
+    .await
+    .map_err(into_error_resp)?;
+
+    if res.rows_affected() == 0 {
+        return Err(lease_lost(&game_id));
+    }
+
+    if let Some(error) = &result.error {
+        sqlx::query!(
+            "UPDATE analysis_attempts
+            SET error = $3
+            WHERE attempt_id = (
+                SELECT MAX(attempt_id) FROM analysis_attempts
+                WHERE game_id = $1 AND worker_id = $2
+            )",
+            game_id,
+            worker.worker_id,
+            error,
+        )
+        .execute(&mut *txn)
+        .await
+        .map_err(into_error_resp)?;
+    }
+
+    txn.commit().await.map_err(into_error_resp)?;
+
+    match result.outcome {
+        JobOutcome::Completed => {
+            tracing::info!(worker.worker_id, game_id, "Worker completed job")
+        }
+        JobOutcome::Failed => tracing::warn!(
+            worker.worker_id,
+            game_id,
+            error = result.error,
+            "Worker failed job"
+        ),
+    }
+
+    Ok(())
+}
+
+/// Save the analysis of a job this worker holds, replacing any earlier one. The body is the
+/// NDJSON described in [`ingest`]. Report the job as Completed afterwards.
+async fn analysis_upload_handler(
+    Extension(database): Extension<PgPool>,
+    Path(game_id): Path<String>,
+    worker: AnalysisWorker,
+    body: axum::body::Bytes,
+) -> Result<Json<AnalysisUploadResult>, Response> {
+    let payload = ingest::parse_payload(&body).map_err(into_ingest_error_resp)?;
+
+    let start = std::time::Instant::now();
+    let rows = ingest::ingest_analysis(&database, &game_id, &payload, Some(&worker.worker_id))
+        .await
+        .map_err(into_ingest_error_resp)?;
+    tracing::info!(
+        worker.worker_id,
+        game_id,
+        rows,
+        engine = payload.analysis_engine_version,
+        "Saved analysis in {:?}",
+        start.elapsed()
+    );
+
+    Ok(Json(AnalysisUploadResult { rows }))
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct AnalysisUploadResult {
+    /// Rows saved across all the analysis tables
+    pub rows: u64,
+}
+
+fn into_ingest_error_resp(e: IngestError) -> Response {
+    match e {
+        IngestError::Invalid(_) => error_resp(axum::http::StatusCode::BAD_REQUEST, e),
+        IngestError::GameNotFound(_) => error_resp(axum::http::StatusCode::NOT_FOUND, e),
+        IngestError::LeaseLost(game_id) => lease_lost(&game_id),
+        IngestError::Other(e) => into_error_resp(format!("{:#}", e)),
+    }
+}
+
+pub fn workers_api_router() -> ApiRouter {
+    ApiRouter::new()
+        .route("/claim", post(claim_handler))
+        .route("/jobs/{game_id}/heartbeat", post(heartbeat_handler))
+        .route("/jobs/{game_id}/result", post(result_handler))
+        .route(
+            "/jobs/{game_id}/analysis",
+            post(analysis_upload_handler).layer(axum::extract::DefaultBodyLimit::max(
+                ingest::MAX_PAYLOAD_BYTES,
+            )),
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn job_status(db: &PgPool, game_id: &str) -> (AnalysisQueueStatus, Option<String>) {
+        let row = sqlx::query!(
+            r#"SELECT status AS "status: AnalysisQueueStatus", worker_id
+            FROM analysis_queue WHERE game_id = $1"#,
+            game_id
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        (row.status, row.worker_id)
+    }
+
+    #[tokio::test]
+    async fn test_job_lease() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let config = Arc::new(<Config as clap::Parser>::parse_from(["openfrontpro"]));
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let game = crate::game_record::FinishedGame::from_value(json).unwrap();
+        let game_id = game.record.info.game_id.clone();
+        crate::import::import_record(&db.pool, &game).await.unwrap();
+        assert!(
+            crate::import::enqueue_analysis(&db.pool, &game_id, None, 0)
+                .await
+                .unwrap()
+        );
+
+        let alice = AnalysisWorker {
+            worker_id: "alice".into(),
+        };
+        let bob = AnalysisWorker {
+            worker_id: "bob".into(),
+        };
+        let claim = |worker: &AnalysisWorker| {
+            claim_handler(
+                Extension(db.pool.clone()),
+                Extension(config.clone()),
+                worker.clone(),
+            )
+        };
+        let heartbeat = |worker: &AnalysisWorker| {
+            heartbeat_handler(
+                Extension(db.pool.clone()),
+                Extension(config.clone()),
+                Path(game_id.clone()),
+                worker.clone(),
+            )
+        };
+        let report = |worker: &AnalysisWorker, outcome| {
+            result_handler(
+                Extension(db.pool.clone()),
+                Path(game_id.clone()),
+                worker.clone(),
+                Json(JobResult {
+                    outcome,
+                    error: None,
+                }),
+            )
+        };
+
+        // Alice gets the only job, so there is nothing left for Bob
+        let res = claim(&alice).await.unwrap();
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        assert_eq!(
+            job_status(&db.pool, &game_id).await,
+            (AnalysisQueueStatus::Running, Some("alice".into()))
+        );
+        let res = claim(&bob).await.unwrap();
+        assert_eq!(res.status(), axum::http::StatusCode::NO_CONTENT);
+
+        // Only the worker holding the lease can renew it
+        let lease = heartbeat(&alice).await.unwrap();
+        assert!(lease.lease_expires_unix_sec > chrono::Utc::now().timestamp());
+        let res = heartbeat(&bob).await.unwrap_err();
+        assert_eq!(res.status(), axum::http::StatusCode::CONFLICT);
+
+        // Nothing was uploaded, so the job can't be completed yet
+        let res = report(&alice, JobOutcome::Completed).await.unwrap_err();
+        assert_eq!(res.status(), axum::http::StatusCode::CONFLICT);
+
+        // Alice stops sending heartbeats, and the job is requeued for Bob
+        sqlx::query!(
+            "UPDATE analysis_queue SET lease_expires_unix_sec = 0 WHERE game_id = $1",
+            game_id
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        crate::tasks::look_for_old_running_games(db.pool.clone(), config.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            job_status(&db.pool, &game_id).await,
+            (AnalysisQueueStatus::Pending, None)
+        );
+        let res = heartbeat(&alice).await.unwrap_err();
+        assert_eq!(res.status(), axum::http::StatusCode::CONFLICT);
+
+        let res = claim(&bob).await.unwrap();
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        let res = report(&alice, JobOutcome::Completed).await.unwrap_err();
+        assert_eq!(res.status(), axum::http::StatusCode::CONFLICT);
+
+        // Alice's upload comes too late, Bob's goes through
+        let upload = |worker: &AnalysisWorker| {
+            analysis_upload_handler(
+                Extension(db.pool.clone()),
+                Path(game_id.clone()),
+                worker.clone(),
+                axum::body::Bytes::from_static(
+                    br#"{"table":"header","analysis_engine_version":"v1"}"#,
+                ),
+            )
+        };
+        let res = upload(&alice).await.unwrap_err();
+        assert_eq!(res.status(), axum::http::StatusCode::CONFLICT);
+        assert_eq!(upload(&bob).await.unwrap().rows, 0);
+        report(&bob, JobOutcome::Completed).await.unwrap();
+        assert_eq!(
+            job_status(&db.pool, &game_id).await,
+            (AnalysisQueueStatus::Completed, Some("bob".into()))
+        );
+
+        // A forced re-run needs its own upload, Bob's analysis from before doesn't count
+        sqlx::query!(
+            "INSERT INTO analysis_queue (game_id, priority) VALUES ($1, 0)",
+            game_id
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        let res = claim(&alice).await.unwrap();
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        let res = report(&alice, JobOutcome::Completed).await.unwrap_err();
+        assert_eq!(res.status(), axum::http::StatusCode::CONFLICT);
+        assert_eq!(upload(&alice).await.unwrap().rows, 0);
+        report(&alice, JobOutcome::Completed).await.unwrap();
+    }
+}
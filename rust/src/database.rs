@@ -42,6 +42,10 @@ pub struct GameConfig {
     pub disabled_units: Vec<String>,
     pub max_players: i32,
     pub player_teams: Option<StringOrInt>,
+    /// Any newer config options that we don't parse yet
+    #[serde(flatten)]
+    #[sqlx(skip)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl GameConfig {
@@ -111,6 +115,24 @@ impl<'a> sqlx::FromRow<'a, sqlx::postgres::PgRow> for APIGetLobby {
     }
 }
 
+/// One poll of a public lobby
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::FromRow, JsonSchema)]
+pub struct LobbySnapshot {
+    pub seen_unix_sec: i64,
+    pub num_clients: i32,
+    pub ms_until_start: i64,
+}
+
+/// How a lobby filled up, oldest snapshot first
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct APILobbyHistory {
+    pub game_id: String,
+    pub game_map: String,
+    pub teams: PlayerTeams,
+    pub max_players: i32,
+    pub snapshots: Vec<LobbySnapshot>,
+}
+
 //impl APIGetLobbyWithConfig {
 //pub fn lobby_config(&self) -> GameConfig {
 //serde_json::from_value(self.lobby_config_json.clone())
@@ -152,6 +174,38 @@ pub struct APIAnalysisQueueEntry {
     pub started_at_unix_sec: Option<i64>,
 }
 
+/// One run of an analysis job
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct APIAnalysisAttempt {
+    pub attempt_id: i64,
+    pub started_unix_sec: i64,
+    pub finished_unix_sec: Option<i64>,
+    /// Unknown for workers that don't go through the worker API. Only shown to admins.
+    pub worker_id: Option<String>,
+    pub analysis_engine_version: Option<String>,
+    /// Unset while the attempt is still running
+    pub outcome: Option<AnalysisQueueStatus>,
+    /// Only shown to admins
+    pub error: Option<String>,
+}
+
+/// The latest analysis job for a game, with every attempt at analyzing it
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct APIAnalysisJob {
+    pub game_id: String,
+    pub status: AnalysisQueueStatus,
+    pub requested_unix_sec: i64,
+    pub started_unix_sec: Option<i64>,
+    pub priority: i32,
+    /// Where a Pending job is in the queue, starting at 1
+    pub position: Option<i64>,
+    /// Only shown to admins
+    pub worker_id: Option<String>,
+    pub lease_expires_unix_sec: Option<i64>,
+    /// Oldest first
+    pub attempts: Vec<APIAnalysisAttempt>,
+}
+
 /// Returns the current Unix timestamp in seconds
 pub fn now_unix_sec() -> i64 {
     std::time::SystemTime::now()
@@ -13,6 +13,7 @@ use crate::database::StringOrInt;
 /// different decoding types depending on the source.
 #[derive(Debug, Clone, serde::Serialize, JsonSchema)]
 #[serde(tag = "group")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum PlayerTeams {
     /// Free for All, represented by 0 in the database and null from the openfront API
     FFA,
@@ -0,0 +1,508 @@
+//! Typed model of the finished game JSON returned by `api.openfront.io/game/{id}` and stored in
+//! `finished_games.result_json`.
+//!
+//! OpenFront changes this format from version to version, so every struct keeps the fields we
+//! don't know about in an `extra` map, and [`Intent`] keeps unknown intent types as raw JSON.
+//! Nothing is lost when a [`GameRecord`] is serialized back out, but it is not byte for byte the
+//! same either (`500` comes back as `500.0`, `null` fields are dropped), so we store the
+//! [`FinishedGame::raw`] JSON we were sent.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::database::GameConfig;
+
+/// A full game record: game info, players, and every turn with its intents
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct GameRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Present on every finished game returned by the OpenFront API
+    #[serde(rename = "gitCommit", default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    pub info: GameInfo,
+    #[serde(default)]
+    pub turns: Vec<Turn>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl GameRecord {
+    /// Finds the player with the given client ID
+    pub fn player(&self, client_id: &str) -> Option<&PlayerRecord> {
+        self.info.players.iter().find(|p| p.client_id == client_id)
+    }
+
+    /// The winning player, if the game was won by a single player
+    pub fn winning_player(&self) -> Option<&PlayerRecord> {
+        match self.info.winner {
+            Some(Winner::Player { ref client_id }) => self.player(client_id),
+            _ => None,
+        }
+    }
+
+    /// Every intent in the game along with the turn it was sent on
+    pub fn intents(&self) -> impl Iterator<Item = (u32, &Intent)> {
+        self.turns
+            .iter()
+            .flat_map(|t| t.intents.iter().map(move |i| (t.turn_number, i)))
+    }
+}
+
+/// A game record along with the JSON it was parsed from
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct FinishedGame {
+    pub record: GameRecord,
+    /// Exactly what the upstream sent, this is what goes in `finished_games.result_json`
+    pub raw: Value,
+}
+
+impl FinishedGame {
+    pub fn from_value(raw: Value) -> serde_json::Result<Self> {
+        let record = GameRecord::deserialize(&raw)?;
+        Ok(FinishedGame { record, raw })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct GameInfo {
+    #[serde(rename = "gameID")]
+    pub game_id: String,
+    pub config: GameConfig,
+    /// Unix timestamp in milliseconds
+    pub start: i64,
+    /// Unix timestamp in milliseconds
+    pub end: i64,
+    /// Game length in seconds
+    pub duration: i64,
+    pub num_turns: i64,
+    #[serde(default)]
+    pub players: Vec<PlayerRecord>,
+    /// Games that were abandoned have no winner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner: Option<Winner>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct PlayerRecord {
+    #[serde(rename = "clientID")]
+    pub client_id: String,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<PlayerStats>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// End of game stats for a player. OpenFront sends every number as a string because they can be
+/// bigger than a javascript number.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct PlayerStats {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attacks: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub betrayals: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gold: Option<Vec<String>>,
+    /// Keyed by boat type, e.g. `trans`, `trade`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boats: Option<BTreeMap<String, Vec<String>>>,
+    /// Keyed by bomb type, e.g. `abomb`, `hbomb`, `mirv`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bombs: Option<BTreeMap<String, Vec<String>>>,
+    /// Keyed by unit type, e.g. `city`, `port`, `defp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub units: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The winner is sent as a list like `["player", clientID]` or `["team", "Red", ...]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(from = "Vec<Value>", into = "Vec<Value>")]
+pub enum Winner {
+    Player {
+        client_id: String,
+    },
+    Team {
+        team: String,
+        /// Newer versions of OpenFront also list the client IDs on the winning team
+        client_ids: Vec<String>,
+    },
+    /// A format we don't know yet
+    Other(Vec<Value>),
+}
+
+impl From<Vec<Value>> for Winner {
+    fn from(value: Vec<Value>) -> Self {
+        let strings: Option<Vec<&str>> = value.iter().map(Value::as_str).collect();
+        match strings.as_deref() {
+            Some(["player", client_id]) => Winner::Player {
+                client_id: client_id.to_string(),
+            },
+            Some(["team", team, client_ids @ ..]) => Winner::Team {
+                team: team.to_string(),
+                client_ids: client_ids.iter().map(|s| s.to_string()).collect(),
+            },
+            _ => Winner::Other(value),
+        }
+    }
+}
+
+impl From<Winner> for Vec<Value> {
+    fn from(winner: Winner) -> Self {
+        match winner {
+            Winner::Player { client_id } => vec!["player".into(), client_id.into()],
+            Winner::Team { team, client_ids } => ["team".to_string(), team]
+                .into_iter()
+                .chain(client_ids)
+                .map(Value::from)
+                .collect(),
+            Winner::Other(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Turn {
+    #[serde(rename = "turnNumber")]
+    pub turn_number: u32,
+    #[serde(default)]
+    pub intents: Vec<Intent>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A single action sent by a player during the game, tagged by `type`
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum Intent {
+    #[serde(rename = "attack")]
+    Attack {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        /// `None` when attacking terra nullius
+        #[serde(rename = "targetID")]
+        target_id: Option<String>,
+        troops: f64,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "cancel_attack")]
+    CancelAttack {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        #[serde(rename = "attackID")]
+        attack_id: String,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "boat")]
+    Boat {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        #[serde(rename = "targetID")]
+        target_id: Option<String>,
+        troops: f64,
+        dst: Option<u64>,
+        #[serde(default)]
+        src: Option<u64>,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "cancel_boat")]
+    CancelBoat {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        #[serde(rename = "unitID")]
+        unit_id: u64,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "spawn")]
+    Spawn {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        name: String,
+        flag: Option<String>,
+        #[serde(rename = "playerType")]
+        player_type: String,
+        x: i32,
+        y: i32,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "build_unit")]
+    BuildUnit {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        unit: String,
+        x: i32,
+        y: i32,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "upgrade_structure")]
+    UpgradeStructure {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        unit: String,
+        #[serde(rename = "unitId")]
+        unit_id: u64,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "move_warship")]
+    MoveWarship {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        #[serde(rename = "unitId")]
+        unit_id: u64,
+        tile: u64,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "troop_ratio")]
+    TroopRatio {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        ratio: f64,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "allianceRequest")]
+    AllianceRequest {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        recipient: String,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "allianceRequestReply")]
+    AllianceRequestReply {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        requestor: String,
+        accept: bool,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "breakAlliance")]
+    BreakAlliance {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        recipient: String,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "targetPlayer")]
+    TargetPlayer {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        target: String,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "embargo")]
+    Embargo {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        #[serde(rename = "targetID")]
+        target_id: String,
+        /// `start` or `stop`
+        action: String,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "donate_troops")]
+    DonateTroops {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        recipient: String,
+        troops: Option<f64>,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "donate_gold")]
+    DonateGold {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        recipient: String,
+        gold: Option<f64>,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "emoji")]
+    Emoji {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        recipient: String,
+        emoji: i64,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    #[serde(rename = "quick_chat")]
+    QuickChat {
+        #[serde(rename = "clientID")]
+        client_id: String,
+        recipient: String,
+        #[serde(rename = "quickChatKey")]
+        quick_chat_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(flatten)]
+        extra: Map<String, Value>,
+    },
+    /// Any intent type we don't model yet, kept as it was sent
+    #[serde(untagged)]
+    Unknown(Value),
+}
+
+impl Intent {
+    /// The client that sent this intent
+    pub fn client_id(&self) -> Option<&str> {
+        match self {
+            Intent::Attack { client_id, .. }
+            | Intent::CancelAttack { client_id, .. }
+            | Intent::Boat { client_id, .. }
+            | Intent::CancelBoat { client_id, .. }
+            | Intent::Spawn { client_id, .. }
+            | Intent::BuildUnit { client_id, .. }
+            | Intent::UpgradeStructure { client_id, .. }
+            | Intent::MoveWarship { client_id, .. }
+            | Intent::TroopRatio { client_id, .. }
+            | Intent::AllianceRequest { client_id, .. }
+            | Intent::AllianceRequestReply { client_id, .. }
+            | Intent::BreakAlliance { client_id, .. }
+            | Intent::TargetPlayer { client_id, .. }
+            | Intent::Embargo { client_id, .. }
+            | Intent::DonateTroops { client_id, .. }
+            | Intent::DonateGold { client_id, .. }
+            | Intent::Emoji { client_id, .. }
+            | Intent::QuickChat { client_id, .. } => Some(client_id),
+            Intent::Unknown(v) => v.get("clientID").and_then(Value::as_str),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_all_example_games() {
+        for (name, contents) in crate::utils::all_games_in_test() {
+            let Ok(json) = serde_json::from_slice::<Value>(contents) else {
+                // Some downloads are empty, these are never stored as finished games
+                assert!(contents.is_empty(), "{name} is not valid JSON");
+                continue;
+            };
+
+            if json.get("error").is_some() {
+                assert!(
+                    serde_json::from_value::<GameRecord>(json).is_err(),
+                    "{name} is an error response and should not parse as a game"
+                );
+                continue;
+            }
+
+            let record: GameRecord = serde_json::from_value(json.clone())
+                .unwrap_or_else(|e| panic!("Failed to parse {name}: {e}"));
+
+            assert_eq!(
+                record.info.players.len(),
+                json["info"]["players"].as_array().unwrap().len()
+            );
+            assert_eq!(record.turns.len(), json["turns"].as_array().unwrap().len());
+            for (_, intent) in record.intents() {
+                assert!(
+                    !matches!(intent, Intent::Unknown(_)),
+                    "{name} has an unknown intent: {intent:?}"
+                );
+                assert!(intent.client_id().is_some());
+            }
+
+            if let Some(Winner::Player { ref client_id }) = record.info.winner {
+                assert_eq!(
+                    record.winning_player().map(|p| &p.client_id),
+                    Some(client_id),
+                    "{name} winner is not a player in the game"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_finished_game_keeps_raw_json() {
+        // The record itself is not enough to store, whole numbers come back as floats
+        let mut json = crate::utils::load_game_in_test("mygame").unwrap();
+        json["turns"][0]["intents"] = serde_json::json!([
+            { "type": "attack", "clientID": "7B35GWaD", "targetID": null, "troops": 500 },
+        ]);
+        let game = FinishedGame::from_value(json.clone()).unwrap();
+        assert_eq!(
+            game.raw["turns"][0]["intents"][0]["troops"].to_string(),
+            "500"
+        );
+        let reserialized = serde_json::to_value(&game.record).unwrap();
+        assert_eq!(
+            reserialized["turns"][0]["intents"][0]["troops"].to_string(),
+            "500.0"
+        );
+    }
+
+    #[test]
+    fn test_game_record_keeps_unknown_data() {
+        let mut json = crate::utils::load_game_in_test("mygame").unwrap();
+        json["info"]["newInfoField"] = serde_json::json!({ "a": 1 });
+        json["info"]["players"][0]["stats"]["newStat"] = serde_json::json!(["5"]);
+        json["turns"][0]["intents"] = serde_json::json!([
+            { "type": "attack", "clientID": "7B35GWaD", "targetID": null, "troops": 1.5, "newField": true },
+            { "type": "new_intent_type", "clientID": "7B35GWaD", "something": [1, 2, 3] },
+        ]);
+
+        let record: GameRecord = serde_json::from_value(json.clone()).unwrap();
+        let intents = &record.turns[0].intents;
+        assert!(matches!(&intents[0], Intent::Attack { extra, .. } if extra["newField"] == true));
+        assert!(matches!(&intents[1], Intent::Unknown(v) if v["type"] == "new_intent_type"));
+        assert_eq!(intents[1].client_id(), Some("7B35GWaD"));
+
+        let out = serde_json::to_value(&record).unwrap();
+        assert_eq!(out["info"]["newInfoField"], json["info"]["newInfoField"]);
+        assert_eq!(out["info"]["players"][0], json["info"]["players"][0]);
+        assert_eq!(out["turns"][0], json["turns"][0]);
+    }
+
+    #[test]
+    fn test_winner_formats() {
+        let player: Winner = serde_json::from_value(serde_json::json!(["player", "abc"])).unwrap();
+        assert_eq!(
+            player,
+            Winner::Player {
+                client_id: "abc".into()
+            }
+        );
+
+        let team: Winner =
+            serde_json::from_value(serde_json::json!(["team", "Red", "abc", "def"])).unwrap();
+        assert_eq!(
+            team,
+            Winner::Team {
+                team: "Red".into(),
+                client_ids: vec!["abc".into(), "def".into()]
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&team).unwrap(),
+            serde_json::json!(["team", "Red", "abc", "def"])
+        );
+
+        let other: Winner = serde_json::from_value(serde_json::json!(["nation", 5])).unwrap();
+        assert!(matches!(other, Winner::Other(_)));
+    }
+}
@@ -0,0 +1,360 @@
+//! Load game records from disk into the database, for games the poller never saw.
+//!
+//! ```sh
+//! openfrontpro import examples/gamedata/ more_games.ndjson --analyze
+//! ```
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::{analysis, game_record::FinishedGame};
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImportArgs {
+    /// `.json` game records, `.ndjson`/`.jsonl` files with one record per line, or directories
+    /// containing either
+    #[clap(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Also add every imported game to the analysis queue
+    #[clap(long)]
+    pub analyze: bool,
+}
+
+/// What happened to a single game record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Inserted,
+    AlreadyPresent,
+}
+
+/// Counts for one file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileReport {
+    pub inserted: usize,
+    pub already_present: usize,
+    pub queued_for_analysis: usize,
+    pub errors: Vec<String>,
+}
+
+pub async fn run_import(database: PgPool, args: &ImportArgs) -> anyhow::Result<()> {
+    let files = collect_files(&args.paths)?;
+    if files.is_empty() {
+        anyhow::bail!("No .json, .ndjson or .jsonl files found");
+    }
+
+    let mut total = FileReport::default();
+    for file in &files {
+        let report = import_file(&database, file, args.analyze).await;
+
+        println!(
+            "{}: {} inserted, {} already present, {} queued, {} failed",
+            file.display(),
+            report.inserted,
+            report.already_present,
+            report.queued_for_analysis,
+            report.errors.len()
+        );
+        for e in &report.errors {
+            println!("    {}", e);
+        }
+
+        total.inserted += report.inserted;
+        total.already_present += report.already_present;
+        total.queued_for_analysis += report.queued_for_analysis;
+        total.errors.extend(report.errors);
+    }
+
+    println!(
+        "Imported {} files: {} inserted, {} already present, {} queued, {} failed",
+        files.len(),
+        total.inserted,
+        total.already_present,
+        total.queued_for_analysis,
+        total.errors.len()
+    );
+
+    Ok(())
+}
+
+/// Expands directories (recursively) into the record files inside them
+pub fn collect_files(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)
+                .with_context(|| format!("Failed to read directory {}", path.display()))?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+
+            let (dirs, entries): (Vec<_>, Vec<_>) = entries.into_iter().partition(|p| p.is_dir());
+            files.extend(entries.into_iter().filter(|p| is_record_file(p)));
+            files.extend(collect_files(&dirs)?);
+        } else {
+            // Files given by name are always read, whatever they are called
+            files.push(path.clone());
+        }
+    }
+
+    Ok(files)
+}
+
+fn is_record_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("json" | "ndjson" | "jsonl")
+    )
+}
+
+fn is_ndjson(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("ndjson" | "jsonl")
+    )
+}
+
+/// Parses a file into game records. A file is either one record, or one record per line.
+pub fn parse_records(path: &Path, contents: &str) -> Vec<anyhow::Result<FinishedGame>> {
+    if !is_ndjson(path) {
+        return vec![parse_record(contents.as_bytes())];
+    }
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            parse_record(line.as_bytes())
+                .with_context(|| format!("Invalid game record on line {}", i + 1))
+        })
+        .collect()
+}
+
+fn parse_record(json: &[u8]) -> anyhow::Result<FinishedGame> {
+    let raw = serde_json::from_slice(json).context("Invalid JSON")?;
+    FinishedGame::from_value(raw).context("Invalid game record")
+}
+
+async fn import_file(database: &PgPool, path: &Path, analyze: bool) -> FileReport {
+    let mut report = FileReport::default();
+
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(c) => c,
+        Err(e) => {
+            report.errors.push(format!("Failed to read file: {}", e));
+            return report;
+        }
+    };
+
+    for game in parse_records(path, &contents) {
+        let game = match game {
+            Ok(r) => r,
+            Err(e) => {
+                report.errors.push(format!("{:#}", e));
+                continue;
+            }
+        };
+
+        let game_id = &game.record.info.game_id;
+        match import_record(database, &game).await {
+            Ok(ImportOutcome::Inserted) => report.inserted += 1,
+            Ok(ImportOutcome::AlreadyPresent) => report.already_present += 1,
+            Err(e) => {
+                report.errors.push(format!("{}: {:#}", game_id, e));
+                continue;
+            }
+        }
+
+        if analyze {
+            match enqueue_analysis(database, game_id, None, 0).await {
+                Ok(true) => report.queued_for_analysis += 1,
+                Ok(false) => {}
+                Err(e) => report.errors.push(format!(
+                    "{}: Failed to queue for analysis: {:#}",
+                    game_id, e
+                )),
+            }
+        }
+    }
+
+    report
+}
+
+/// Inserts the game and its lobby. Importing the same game twice does nothing.
+pub async fn import_record(
+    database: &PgPool,
+    game: &FinishedGame,
+) -> anyhow::Result<ImportOutcome> {
+    let info = &game.record.info;
+    let game_id = info.game_id.as_str();
+    if game_id.len() != 8 || !game_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        anyhow::bail!("Invalid game_id: {}", game_id);
+    }
+
+    let teams: i32 = info.config.teams().into();
+    let start_unix_sec = info.start / 1000;
+
+    let mut txn = database.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO
+            lobbies (game_id, teams, max_players, game_map, approx_num_players, first_seen_unix_sec, last_seen_unix_sec, completed, lobby_config_json)
+        VALUES
+            ($1, $2, $3, $4, $5, $6, $6, true, $7)
+        ON CONFLICT (game_id)
+        DO UPDATE
+            SET completed = true
+        ",
+        game_id,
+        teams,
+        info.config.max_players,
+        info.config.game_map,
+        info.players.len() as i32,
+        start_unix_sec,
+        serde_json::to_value(&info.config)?,
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    let res = sqlx::query!(
+        "INSERT INTO finished_games (game_id, result_json, is_ok)
+        VALUES ($1, $2, true)
+        ON CONFLICT (game_id) DO NOTHING",
+        game_id,
+        game.raw,
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    txn.commit().await?;
+
+    if res.rows_affected() == 0 {
+        return Ok(ImportOutcome::AlreadyPresent);
+    }
+
+    let intents = analysis::intents::analyze_intents(&game.record);
+    analysis::intents::save_intent_analysis(database, game_id, &intents).await?;
+
+    Ok(ImportOutcome::Inserted)
+}
+
+/// Queue priority for games someone asked to have analyzed. Games queued automatically use 0 or
+/// the priority of their analysis rule, so they wait behind these.
+pub const REQUESTED_PRIORITY: i32 = 100;
+
+/// Returns false if the game is already queued or running, or was analyzed before
+pub async fn enqueue_analysis(
+    database: &PgPool,
+    game_id: &str,
+    requesting_user_id: Option<&str>,
+    priority: i32,
+) -> anyhow::Result<bool> {
+    let res = sqlx::query!(
+        "INSERT INTO analysis_queue (game_id, requesting_user_id, priority)
+        SELECT $1, $2, $3
+        WHERE NOT EXISTS (SELECT 1 FROM analysis_1.completed_analysis WHERE game_id = $1)
+        ON CONFLICT (game_id) WHERE status IN ('Pending', 'Running') DO NOTHING",
+        game_id,
+        requesting_user_id,
+        priority,
+    )
+    .execute(database)
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+/// Largest game record we accept in an upload, after decompressing
+pub const MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+/// Parses an uploaded game record, which may be gzipped. Gzip is detected from the magic bytes,
+/// so it doesn't matter what the client set as the content type.
+pub fn decode_upload(body: &[u8]) -> anyhow::Result<FinishedGame> {
+    let is_gzip = body.starts_with(&[0x1f, 0x8b]);
+    if !is_gzip {
+        return parse_record(body);
+    }
+
+    let mut json = Vec::new();
+    flate2::read::GzDecoder::new(body)
+        .take(MAX_UPLOAD_BYTES as u64 + 1)
+        .read_to_end(&mut json)
+        .context("Invalid gzip")?;
+    if json.len() > MAX_UPLOAD_BYTES {
+        anyhow::bail!("Game record is larger than {} bytes", MAX_UPLOAD_BYTES);
+    }
+
+    parse_record(&json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_records() {
+        let (_, game) = crate::utils::all_games_in_test()
+            .find(|(name, _)| *name == "mygame.json")
+            .unwrap();
+        let game = std::str::from_utf8(game).unwrap();
+
+        let single = parse_records(Path::new("mygame.json"), game);
+        assert_eq!(single.len(), 1);
+        assert_eq!(
+            single[0]
+                .as_ref()
+                .unwrap()
+                .record
+                .info
+                .config
+                .teams()
+                .to_string(),
+            "FFA"
+        );
+
+        // One record per line, with a blank line and a broken line in the middle
+        let line: serde_json::Value = serde_json::from_str(game).unwrap();
+        let line = serde_json::to_string(&line).unwrap();
+        let ndjson = format!("{line}\n\n{{\"error\": \"Not found\"}}\n{line}\n");
+        let many = parse_records(Path::new("games.ndjson"), &ndjson);
+        assert_eq!(many.len(), 3);
+        assert!(many[0].is_ok());
+        assert!(many[1].as_ref().unwrap_err().to_string().contains("line 3"));
+        assert!(many[2].is_ok());
+    }
+
+    #[test]
+    fn test_decode_upload() {
+        use std::io::Write;
+
+        let (_, game) = crate::utils::all_games_in_test()
+            .find(|(name, _)| *name == "mygame.json")
+            .unwrap();
+        let plain = decode_upload(game).unwrap();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(game).unwrap();
+        let gzipped = decode_upload(&gz.finish().unwrap()).unwrap();
+        assert_eq!(
+            plain.raw,
+            serde_json::from_slice::<serde_json::Value>(game).unwrap()
+        );
+        assert_eq!(plain.raw, gzipped.raw);
+
+        assert!(decode_upload(br#"{"error": "Not found"}"#).is_err());
+        assert!(decode_upload(&[0x1f, 0x8b, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_collect_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/gamedata");
+        let files = collect_files(&[dir]).unwrap();
+        assert!(!files.is_empty());
+        assert!(files.iter().all(|f| is_record_file(f)));
+        assert!(files.windows(2).all(|w| w[0] < w[1]));
+    }
+}
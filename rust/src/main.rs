@@ -18,13 +18,24 @@ use tokio::time::Duration;
 use tower_http::services::ServeDir;
 use utils::serve_file;
 
-use crate::{database::APIGetLobby, oauth::OAuthBundle};
+use crate::{
+    api::openfrontapi::OpenFrontClient,
+    oauth::OAuthBundle,
+    settings::Settings,
+    tasks::leader::LeaderLock,
+    tasks::queue_events::QueueEvents,
+    tasks::registry::TaskRegistry,
+    tasks::schedule::{Schedule, TaskOverride},
+};
 
 mod analysis;
 mod api;
 mod database;
+mod game_record;
+mod import;
 mod middleware;
 mod oauth;
+mod settings;
 mod tasks;
 mod utils;
 
@@ -49,7 +60,7 @@ pub struct Config {
     pub useragent: Option<String>,
 
     #[clap(long, env)]
-    /// User agent to use for requests to OpenFront API
+    /// Cookie to use for requests to OpenFront API
     pub cookie: Option<String>,
 
     #[clap(long, env, default_value = "https://openfront.io/api/public_lobbies")]
@@ -58,6 +69,47 @@ pub struct Config {
     #[clap(long, env, default_value = "https://api.openfront.io")]
     pub openfront_api_url: String,
 
+    #[clap(long, env, default_value = "15")]
+    /// Timeout in seconds for each request to the OpenFront API
+    pub openfront_timeout_secs: u64,
+
+    #[clap(long, env, default_value = "4")]
+    /// Max requests per second to the OpenFront API, shared by every task. 0 to disable.
+    pub openfront_requests_per_sec: f64,
+
+    #[clap(long, env, default_value = "3")]
+    /// How many times to retry an OpenFront API request after a timeout, 429 or 5xx
+    pub openfront_max_retries: u32,
+
+    #[clap(long, env, default_value = "1000")]
+    /// Wait before the first retry, doubled on every retry after that
+    pub openfront_retry_backoff_ms: u64,
+
+    #[clap(long, env, default_value = "8")]
+    /// How many times to try downloading a finished game before giving up on it
+    pub game_fetch_max_attempts: u32,
+
+    #[clap(long, env, default_value = "900")]
+    /// Wait before trying a game download again, doubled after every failed attempt
+    pub game_fetch_retry_base_secs: u64,
+
+    #[clap(long, env, default_value = "https://openfront.pro")]
+    /// openfront.pro instance to copy lobbies and games from with the PullLobbiesFromPROD task
+    pub mirror_upstream_url: String,
+
+    #[clap(long, env, default_value = "86400")]
+    /// How far back to look for lobbies to mirror, in seconds
+    pub mirror_lookback_secs: i64,
+
+    #[clap(long, env)]
+    /// Also copy the analysis of mirrored games the upstream has analyzed. Games the upstream
+    /// can't send the analysis for are queued for analysis here instead.
+    pub mirror_analysis: bool,
+
+    #[clap(long, env, default_value = "30")]
+    /// On SIGTERM/SIGINT, how long to wait for requests and background tasks to finish, in total
+    pub shutdown_timeout_secs: u64,
+
     #[clap(long, env, default_value = "./frontend")]
     pub frontend_folder: String,
 
@@ -74,11 +126,46 @@ pub struct Config {
     )]
     pub discord_redirect_uri: String,
 
+    #[clap(long, env)]
+    /// Without Discord OAuth, give the test user every permission. Only for local development.
+    pub dev_admin: bool,
+
+    #[clap(long, env, default_value = "86400")]
+    /// How long a login lasts, in seconds
+    pub session_ttl_secs: i64,
+
+    #[clap(long, env)]
+    /// Push a session's expiry back to `session_ttl_secs` from now whenever it is used
+    pub session_sliding_expiration: bool,
+
+    #[clap(long, env, default_value = "120")]
+    /// How long an analysis worker holds a job without sending a heartbeat, in seconds
+    pub worker_lease_secs: i64,
+
+    #[clap(long, env, default_value = "5")]
+    /// With several replicas, how often a standby tries to take over a singleton task
+    pub leader_retry_secs: u64,
+
+    #[clap(long, env, value_delimiter = '|')]
+    /// Change when a task runs, e.g. `LookForTrackedPlayerGames:cron=0,30 * * * *;timeout=600`.
+    /// Separate several with `|`. See `TaskOverride` for every setting.
+    pub task_settings: Vec<TaskOverride>,
+
     #[clap(long, env, short = 'd')]
     pub disable_tasks: Vec<ActiveTasks>,
 
     #[clap(long, env, short = 'e')]
     pub extra_tasks: Vec<ActiveTasks>,
+
+    #[clap(subcommand)]
+    /// Run a one-off command instead of the server
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum Command {
+    /// Import finished game records from files into the database
+    Import(import::ImportArgs),
 }
 
 impl Config {
@@ -110,34 +197,56 @@ pub enum ActiveTasks {
     LookForFinishedLobbies,
     /// Download game data for the games that are in the analysis queue
     LookForNewGamesInAnalysisQueue,
-    /// If analysis takes longer than 30 minutes, then we update state = Stalled
+    /// Put jobs whose worker lease expired back to Pending, and mark other runs older than 60
+    /// minutes as Stalled
     LookForOldRunningGames,
-    /// TODO If a session has expired, we can delete it from db
+    /// Delete sessions that have expired
     LookForOldSessions,
     /// For every registered player we have with an openfront ID, look for their games
     LookForTrackedPlayerGames,
-    /// Extra tasks: pull from prpod
+    /// Extra tasks: mirror lobbies and games from another openfront.pro, see `mirror_upstream_url`
     PullLobbiesFromPROD,
 }
 
 /// Spawn the background worker tasks
-async fn launch_tasks(config: Arc<Config>, database: PgPool) -> anyhow::Result<()> {
+async fn launch_tasks(
+    config: Arc<Config>,
+    database: PgPool,
+    ofclient: Arc<OpenFrontClient>,
+    registry: TaskRegistry,
+    queue_events: QueueEvents,
+    settings: Settings,
+) -> anyhow::Result<()> {
     if config.disable_tasks.contains(&ActiveTasks::All) {
         tracing::info!("All tasks are disabled, skipping task launch");
         return Ok(());
     }
 
+    // With several replicas, only one of them should run each poller: they talk to upstream or
+    // sweep shared tables. The ones that run to completion get a timeout, so a hung upstream
+    // can't stall them forever.
+    let leader = |task| {
+        LeaderLock::new(
+            database.clone(),
+            task,
+            Duration::from_secs(config.leader_retry_secs),
+        )
+    };
+
     if config
         .extra_tasks
         .contains(&ActiveTasks::LookForOpenfrontLobbies)
     {
         let db = database.clone();
         let cfg = config.clone();
-        let ofapi = config.clone();
+        let ofapi = ofclient.clone();
+        let shutdown = registry.shutdown_token();
         keep_task_alive(
-            move || look_for_new_games(ofapi.clone(), db.clone(), cfg.clone()),
+            registry.register(ActiveTasks::LookForOpenfrontLobbies),
+            move || look_for_new_games(ofapi.clone(), db.clone(), cfg.clone(), shutdown.clone()),
             TaskSettings {
-                sleep_time: Duration::ZERO,
+                schedule: Schedule::Every(Duration::ZERO),
+                leader: Some(leader(ActiveTasks::LookForOpenfrontLobbies)),
                 ..Default::default()
             },
         );
@@ -149,11 +258,17 @@ async fn launch_tasks(config: Arc<Config>, database: PgPool) -> anyhow::Result<(
     {
         let db = database.clone();
         let cfg = config.clone();
-        let ofapi = config.clone();
+        let ofapi = ofclient.clone();
+        let handle = registry.register(ActiveTasks::LookForNewGamesInAnalysisQueue);
+        queue_events.wake_on_pending(handle.clone(), registry.shutdown_token());
         keep_task_alive(
+            handle,
             move || look_for_new_games_in_analysis_queue(ofapi.clone(), db.clone(), cfg.clone()),
             TaskSettings {
-                sleep_time: Duration::from_secs(5),
+                // New games wake this up right away, so this only catches missed notifications
+                schedule: Schedule::Every(Duration::from_secs(60)),
+                timeout: Some(Duration::from_secs(60 * 10)),
+                leader: Some(leader(ActiveTasks::LookForNewGamesInAnalysisQueue)),
                 ..Default::default()
             },
         );
@@ -165,17 +280,21 @@ async fn launch_tasks(config: Arc<Config>, database: PgPool) -> anyhow::Result<(
     {
         let db = database.clone();
         let cfg = config.clone();
-        let ofapi = config.clone();
+        let ofapi = ofclient.clone();
+        let settings = settings.clone();
         keep_task_alive(
-            move || look_for_lobby_games(ofapi.clone(), db.clone(), cfg.clone()),
+            registry.register(ActiveTasks::LookForFinishedLobbies),
+            move || look_for_lobby_games(ofapi.clone(), db.clone(), cfg.clone(), settings.clone()),
             TaskSettings {
-                sleep_time: Duration::from_secs(60 * 5),
+                schedule: Schedule::Every(Duration::from_secs(60 * 5)),
+                timeout: Some(Duration::from_secs(60 * 10)),
+                leader: Some(leader(ActiveTasks::LookForFinishedLobbies)),
                 ..Default::default()
             },
         );
     }
 
-    // If analysis takes longer than 30 minutes, then we update state = Stalled
+    // Requeue jobs with expired worker leases, and mark other long runs as Stalled
     // LookForOldRunningGames,
     if !config
         .disable_tasks
@@ -184,28 +303,50 @@ async fn launch_tasks(config: Arc<Config>, database: PgPool) -> anyhow::Result<(
         let db = database.clone();
         let cfg = config.clone();
         keep_task_alive(
+            registry.register(ActiveTasks::LookForOldRunningGames),
             move || tasks::look_for_old_running_games(db.clone(), cfg.clone()),
             TaskSettings {
-                sleep_time: Duration::from_secs(60 * 5),
+                // Often enough that a job from a dead worker doesn't wait long for another
+                schedule: Schedule::Every(Duration::from_secs(30)),
+                leader: Some(leader(ActiveTasks::LookForOldRunningGames)),
+                ..Default::default()
+            },
+        );
+    }
+
+    if !config
+        .disable_tasks
+        .contains(&ActiveTasks::LookForOldSessions)
+    {
+        let db = database.clone();
+        keep_task_alive(
+            registry.register(ActiveTasks::LookForOldSessions),
+            move || tasks::look_for_old_sessions(db.clone()),
+            TaskSettings {
+                schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+                leader: Some(leader(ActiveTasks::LookForOldSessions)),
                 ..Default::default()
             },
         );
     }
 
-    // TODO If a session has expired, we can delete it from db
-    // LookForOldSessions,
-    //
     // For every registered player we have with an openfront ID, look for their games
     if !config
         .disable_tasks
         .contains(&ActiveTasks::LookForTrackedPlayerGames)
     {
         let db = database.clone();
-        let ofapi = config.clone();
+        let ofapi = ofclient.clone();
+        let settings = settings.clone();
         keep_task_alive(
-            move || tasks::look_for_tracked_player_games(db.clone(), ofapi.clone()),
+            registry.register(ActiveTasks::LookForTrackedPlayerGames),
+            move || {
+                tasks::look_for_tracked_player_games(db.clone(), ofapi.clone(), settings.clone())
+            },
             TaskSettings {
-                sleep_time: Duration::from_secs(60),
+                schedule: Schedule::Every(Duration::from_secs(60)),
+                timeout: Some(Duration::from_secs(60 * 10)),
+                leader: Some(leader(ActiveTasks::LookForTrackedPlayerGames)),
                 ..Default::default()
             },
         );
@@ -215,21 +356,15 @@ async fn launch_tasks(config: Arc<Config>, database: PgPool) -> anyhow::Result<(
         .extra_tasks
         .contains(&ActiveTasks::PullLobbiesFromPROD)
     {
-        let _db = database.clone();
-        let _cfg = config.clone();
+        let db = database.clone();
+        let cfg = config.clone();
         keep_task_alive(
-            move || async {
-                let _oldlobbies = reqwest::get("https://openfront.pro/api/v1/lobbies")
-                    .await
-                    .context("Failed to fetch lobbies from openfront.pro")?
-                    .json::<Vec<APIGetLobby>>()
-                    .await
-                    .context("Failed to parse lobbies from openfront.pro")?;
-
-                Ok(())
-            },
+            registry.register(ActiveTasks::PullLobbiesFromPROD),
+            move || tasks::mirror::pull_from_upstream(db.clone(), cfg.clone()),
             TaskSettings {
-                sleep_time: Duration::from_secs(60 * 60),
+                schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+                timeout: Some(Duration::from_secs(60 * 30)),
+                leader: Some(leader(ActiveTasks::PullLobbiesFromPROD)),
                 ..Default::default()
             },
         );
@@ -257,6 +392,9 @@ async fn main() -> anyhow::Result<()> {
         tracing::info!("Discord OAuth is enabled");
     } else {
         tracing::warn!("Discord OAuth is not enabled, no client ID or secret provided");
+        if config.dev_admin {
+            tracing::warn!("Everyone is the test user and an admin (--dev-admin)");
+        }
     }
 
     let database = PgPoolOptions::new()
@@ -267,15 +405,32 @@ async fn main() -> anyhow::Result<()> {
         .await
         .context("Failed to create database connection pool")?;
 
+    // Everything below needs the tables to exist, so this has to finish first
+    match sqlx::migrate!("./migrations").run(&database).await {
+        Ok(_) => tracing::info!("Database migrations applied successfully"),
+        Err(e) => tracing::error!("Failed to apply database migrations: {}", e),
+    }
+
+    if let Some(Command::Import(args)) = &config.command {
+        return import::run_import(database, args).await;
+    }
+
+    let ofclient =
+        Arc::new(OpenFrontClient::new(&config).context("Failed to create OpenFront API client")?);
     let config = std::sync::Arc::new(config);
+    let task_registry = TaskRegistry::with_overrides(config.task_settings.clone());
+    let settings = Settings::new(database.clone());
+    if let Err(e) = settings.reload().await {
+        tracing::error!("Failed to load runtime settings, using defaults: {}", e);
+    }
+    tokio::spawn(settings.clone().listen(task_registry.shutdown_token()));
 
-    let db = database.clone();
-    tokio::spawn(async move {
-        match sqlx::migrate!("./migrations").run(&db).await {
-            Ok(_) => tracing::info!("Database migrations applied successfully"),
-            Err(e) => tracing::error!("Failed to apply database migrations: {}", e),
-        }
-    });
+    let queue_events = QueueEvents::default();
+    tokio::spawn(
+        queue_events
+            .clone()
+            .listen(database.clone(), task_registry.shutdown_token()),
+    );
 
     // TODO: Make sure we don't have vulnerabilites around the frontend because CORS.
     // (especially around oauth stuff)
@@ -334,6 +489,9 @@ async fn main() -> anyhow::Result<()> {
         .finish_api(&mut openapi)
         .layer(Extension(openapi.clone()))
         .layer(Extension(config.clone()))
+        .layer(Extension(ofclient.clone()))
+        .layer(Extension(task_registry.clone()))
+        .layer(Extension(settings.clone()))
         .layer(
             // TODO Figure out how to embed a "request_id" without a lot of boilerplate so that we
             // can tie the request and response together in the logs.
@@ -355,17 +513,83 @@ async fn main() -> anyhow::Result<()> {
     //  - Looking for new lobbies
     //  - Downloading game data
     //  - Preparing the launch the simulation code
-    launch_tasks(config.clone(), database.clone())
-        .await
-        .context("Failed to launch async tasks")?;
+    launch_tasks(
+        config.clone(),
+        database.clone(),
+        ofclient.clone(),
+        task_registry.clone(),
+        queue_events.clone(),
+        settings.clone(),
+    )
+    .await
+    .context("Failed to launch async tasks")?;
 
     let listener = tokio::net::TcpListener::bind(("0.0.0.0", config.port)).await?;
     tracing::info!("HTTP Server Listening on {}", listener.local_addr()?);
-    axum::serve(
+
+    let shutdown = task_registry.shutdown_token();
+    let server = axum::serve(
         listener,
         fin.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .await?;
+    .with_graceful_shutdown(shutdown_signal(shutdown.clone()));
+    let mut server = std::pin::pin!(std::future::IntoFuture::into_future(server));
+
+    let server_stopped = tokio::select! {
+        res = &mut server => {
+            res?;
+            true
+        }
+        _ = shutdown.cancelled() => false,
+    };
+
+    // Requests and background tasks share one deadline, each only gets the time that is left
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(config.shutdown_timeout_secs);
+    if !server_stopped {
+        match tokio::time::timeout_at(deadline, &mut server).await {
+            Ok(res) => res?,
+            Err(_) => {
+                tracing::warn!("Some HTTP requests did not finish before the shutdown timeout")
+            }
+        }
+    }
+
+    tracing::info!("HTTP server stopped, waiting for background tasks...");
+    let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+    if !task_registry.shutdown(remaining).await {
+        tracing::warn!("Some background tasks did not finish before the shutdown timeout");
+    }
+
+    database.close().await;
+    tracing::info!("Shutdown complete");
+
+    Ok(())
+}
+
+/// Resolves on SIGINT or SIGTERM, and cancels `shutdown` so every task starts stopping too
+async fn shutdown_signal(shutdown: tokio_util::sync::CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT, shutting down"),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down"),
+        _ = shutdown.cancelled() => {}
+    }
 
-    unreachable!("Server stopped unexpectedly");
+    shutdown.cancel();
 }
@@ -131,8 +131,89 @@ CREATE TABLE social.user_sessions (
 pub struct APIUser {
     pub user_id: String,
     pub username: String,
+    /// From `social.user_permissions`
+    pub permissions: Vec<String>,
+    /// The session this request was authenticated with. `None` for the test user.
+    pub session_id: Option<i32>,
 }
 
+/// Things only some users are allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Everything below
+    Admin,
+    /// Upload game records with `POST /api/v1/games/import`
+    ImportGames,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Admin => "admin",
+            Permission::ImportGames => "import_games",
+        }
+    }
+}
+
+impl APIUser {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions
+            .iter()
+            .any(|p| p == permission.as_str() || p == Permission::Admin.as_str())
+    }
+
+    /// 403 unless the user has this permission
+    #[allow(clippy::result_large_err)]
+    pub fn require(&self, permission: Permission) -> std::result::Result<(), Response> {
+        if self.has_permission(permission) {
+            return Ok(());
+        }
+
+        Err(Response::builder()
+            .status(axum::http::StatusCode::FORBIDDEN)
+            .header(axum::http::header::CONTENT_TYPE, "text/plain")
+            .body(axum::body::Body::from(format!(
+                "Sorry, you need the {} permission to do this.",
+                permission.as_str()
+            )))
+            .expect("Failed to build response for error message"))
+    }
+}
+
+/// The session token from the `session_token` cookie or an `Authorization: Bearer` header
+pub fn session_token(headers: &axum::http::HeaderMap) -> Option<String> {
+    CookieJar::from_headers(headers)
+        .get("session_token")
+        .map(|c| c.value().to_string())
+        .or_else(|| {
+            headers
+                .get("Authorization")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|s| s.strip_prefix("Bearer "))
+                .map(|s| s.to_string())
+        })
+}
+
+/// For endpoints anyone can call, that show more to some users. A missing or invalid session is
+/// `None` rather than a 401.
+impl<S: Sync> OptionalFromRequestParts<S> for APIUser {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> std::result::Result<Option<Self>, Self::Rejection> {
+        Ok(
+            <APIUser as FromRequestParts<S>>::from_request_parts(parts, state)
+                .await
+                .ok(),
+        )
+    }
+}
+
+/// Lets handlers that take an [`APIUser`] show up in the OpenAPI docs
+impl aide::OperationInput for APIUser {}
+
 impl<S: Sync> FromRequestParts<S> for APIUser {
     type Rejection = axum::response::Response;
 
@@ -140,27 +221,28 @@ impl<S: Sync> FromRequestParts<S> for APIUser {
         parts: &mut axum::http::request::Parts,
         state: &S,
     ) -> std::result::Result<Self, Self::Rejection> {
-        if let Some(Extension(config)) = parts.extract::<Extension<Arc<crate::Config>>>().await.ok() {
-            if config.get_discord_oauth().is_none() {
-                return Ok(APIUser {
-                    user_id: "testuser".to_string(),
-                    username: "Test User".to_string(),
-                });
-            }
+        let config = parts
+            .extract::<Extension<Arc<crate::Config>>>()
+            .await
+            .ok()
+            .map(|Extension(config)| config);
+
+        if let Some(config) = &config
+            && config.get_discord_oauth().is_none()
+        {
+            return Ok(APIUser {
+                user_id: "testuser".to_string(),
+                username: "Test User".to_string(),
+                permissions: if config.dev_admin {
+                    vec![Permission::Admin.as_str().to_string()]
+                } else {
+                    vec![]
+                },
+                session_id: None,
+            });
         }
 
-        let cookies = CookieJar::from_headers(&parts.headers);
-        let session_token = cookies
-            .get("session_token")
-            .map(|c| c.value().to_string())
-            .or_else(|| {
-                parts
-                    .headers
-                    .get("Authorization")
-                    .and_then(|h| h.to_str().ok())
-                    .and_then(|s| s.strip_prefix("Bearer "))
-                    .map(|s| s.to_string())
-            });
+        let session_token = session_token(&parts.headers);
 
         let rejection = |msg: &str| {
             Response::builder()
@@ -185,7 +267,13 @@ impl<S: Sync> FromRequestParts<S> for APIUser {
         let user = sqlx::query_as!(
             APIUser,
             r#"
-            SELECT u.id AS user_id, u.username
+            SELECT
+                u.id AS user_id,
+                u.username,
+                ARRAY(
+                    SELECT p.permission FROM social.user_permissions p WHERE p.user_id = u.id
+                ) AS "permissions!",
+                s.session_id AS "session_id?"
             FROM social.registered_users u
             JOIN social.user_sessions s ON s.user_id = u.id
             WHERE
@@ -201,6 +289,30 @@ impl<S: Sync> FromRequestParts<S> for APIUser {
             rejection("Invalid session token or session expired. Please log in with discord again.")
         })?;
 
+        if let Some(config) = &config
+            && config.session_sliding_expiration
+            && let Some(session_id) = user.session_id
+        {
+            // Only write when a good part of the session has been used, not on every request
+            let res = sqlx::query!(
+                r#"
+                UPDATE social.user_sessions
+                SET expires_at_unix_sec = EXTRACT(EPOCH FROM NOW()) + $2::BIGINT
+                WHERE
+                    session_id = $1
+                    AND expires_at_unix_sec < EXTRACT(EPOCH FROM NOW()) + $2::BIGINT * 0.9
+                "#,
+                session_id,
+                config.session_ttl_secs,
+            )
+            .execute(&db_extension)
+            .await;
+
+            if let Err(e) = res {
+                tracing::error!(session_id, "Failed to extend session: {}", e);
+            }
+        }
+
         Ok(user)
     }
 }
@@ -283,13 +395,18 @@ async fn callback_api_handler(
         WITH new_token AS (
             SELECT encode(gen_random_bytes(20), 'base64') AS token
         )
-        INSERT INTO social.user_sessions (user_id, session_token_hash)
-        VALUES ($1, encode(digest((SELECT token FROM new_token), 'sha256'), 'hex'))
+        INSERT INTO social.user_sessions (user_id, session_token_hash, expires_at_unix_sec)
+        VALUES (
+            $1,
+            encode(digest((SELECT token FROM new_token), 'sha256'), 'hex'),
+            EXTRACT(EPOCH FROM NOW()) + $2::BIGINT
+        )
         RETURNING (
             SELECT token FROM new_token
         ) AS session_token
         "#,
-        user_id
+        user_id,
+        config.session_ttl_secs,
     )
     .fetch_one(&database)
     .await
@@ -299,13 +416,7 @@ async fn callback_api_handler(
         .session_token
         .expect("How is is possible to not have a session token?");
 
-    // Determine if we're in production based on redirect URI
-    let is_production = cfg.redirect_uri.starts_with("https://");
-    let cookie_attributes = if is_production {
-        "; Secure; SameSite=Lax"
-    } else {
-        "; SameSite=Lax"
-    };
+    let cookie_attributes = cookie_attributes(&cfg);
 
     let res = Response::builder()
         .status(axum::http::StatusCode::FOUND)
@@ -341,6 +452,17 @@ async fn callback_api_handler(
     Ok(res)
 }
 
+/// Extra attributes for the login cookies
+pub fn cookie_attributes(cfg: &OAuthBundle) -> &'static str {
+    // Determine if we're in production based on redirect URI
+    let is_production = cfg.redirect_uri.starts_with("https://");
+    if is_production {
+        "; Secure; SameSite=Lax"
+    } else {
+        "; SameSite=Lax"
+    }
+}
+
 async fn login_redir_handler(Extension(config): Extension<Arc<Config>>) -> Response {
     let state = "some_random_state"; // This should be a securely generated random state
 
@@ -362,3 +484,30 @@ pub fn routes() -> ApiRouter {
         .route("/callback", axum::routing::get(callback_api_handler))
         .route("/login", axum::routing::get(login_redir_handler))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    async fn test_user(args: &[&str]) -> APIUser {
+        let config = crate::Config::parse_from(["openfrontpro"].iter().chain(args));
+        let (mut parts, _) = axum::http::Request::new(()).into_parts();
+        parts.extensions.insert(Arc::new(config));
+        <APIUser as FromRequestParts<()>>::from_request_parts(&mut parts, &())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_test_user_permissions() {
+        let user = test_user(&[]).await;
+        assert_eq!(user.user_id, "testuser");
+        assert!(!user.has_permission(Permission::ImportGames));
+        assert!(user.require(Permission::Admin).is_err());
+
+        let admin = test_user(&["--dev-admin"]).await;
+        assert!(admin.has_permission(Permission::Admin));
+        assert!(admin.has_permission(Permission::ImportGames));
+    }
+}
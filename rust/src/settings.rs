@@ -0,0 +1,286 @@
+//! Settings that admins can change while the server runs, stored in the `config` table.
+//!
+//! Every key the server knows about is listed in [`KNOWN_SETTINGS`] with its type and default.
+//! Values are cached in [`Settings`], which reloads whenever the table changes, so tasks can read
+//! them as often as they like.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde_json::Value;
+use sqlx::{PgPool, postgres::PgListener};
+use tokio_util::sync::CancellationToken;
+
+/// Every known setting, typed. Field names are the keys in the `config` table.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct RuntimeSettings {
+    pub auto_analysis_enabled: bool,
+    pub finished_game_check_delay_ms: i64,
+    pub tracked_player_check_interval_secs: i64,
+    pub max_pending_analyses_per_user: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum SettingKind {
+    Bool,
+    Integer { min: i64, max: i64 },
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+pub struct SettingDef {
+    pub key: &'static str,
+    pub description: &'static str,
+    #[serde(flatten)]
+    pub kind: SettingKind,
+    /// As stored in the `config` table
+    pub default: &'static str,
+}
+
+/// A value as sent to the API. Strings are accepted for any type and parsed.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SettingValue {
+    Bool(bool),
+    Integer(i64),
+    Text(String),
+}
+
+pub const KNOWN_SETTINGS: &[SettingDef] = &[
+    SettingDef {
+        key: "auto_analysis_enabled",
+        description: "Queue finished games for analysis according to the analysis rules",
+        kind: SettingKind::Bool,
+        default: "true",
+    },
+    SettingDef {
+        key: "finished_game_check_delay_ms",
+        description: "How long to wait between games when checking if lobbies have finished",
+        kind: SettingKind::Integer {
+            min: 0,
+            max: 60_000,
+        },
+        default: "1000",
+    },
+    SettingDef {
+        key: "tracked_player_check_interval_secs",
+        description: "How often to look for new games of each tracked player",
+        kind: SettingKind::Integer {
+            min: 60,
+            max: 7 * 24 * 60 * 60,
+        },
+        default: "1800",
+    },
+    SettingDef {
+        key: "max_pending_analyses_per_user",
+        description: "How many games one user can have waiting for analysis at a time",
+        kind: SettingKind::Integer { min: 1, max: 1000 },
+        default: "5",
+    },
+];
+
+pub fn find_setting(key: &str) -> Option<&'static SettingDef> {
+    KNOWN_SETTINGS.iter().find(|s| s.key == key)
+}
+
+impl SettingDef {
+    /// Checks a value as stored in the `config` table
+    pub fn parse(&self, raw: &str) -> Result<Value, String> {
+        let raw = raw.trim();
+        match self.kind {
+            SettingKind::Bool => match raw {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => Err(format!("{} must be true or false", self.key)),
+            },
+            SettingKind::Integer { min, max } => match raw.parse::<i64>() {
+                Ok(i) if (min..=max).contains(&i) => Ok(Value::from(i)),
+                _ => Err(format!(
+                    "{} must be a whole number from {} to {}",
+                    self.key, min, max
+                )),
+            },
+        }
+    }
+
+    /// Checks a value sent to the API. Returns the text to store.
+    pub fn validate(&self, value: &SettingValue) -> Result<String, String> {
+        let raw = match value {
+            SettingValue::Bool(b) => b.to_string(),
+            SettingValue::Integer(i) => i.to_string(),
+            SettingValue::Text(s) => s.clone(),
+        };
+        Ok(self.parse(&raw)?.to_string())
+    }
+}
+
+impl RuntimeSettings {
+    /// Known keys missing from `stored`, or with invalid values, get their default
+    pub fn from_stored(stored: &HashMap<String, String>) -> Self {
+        let values: serde_json::Map<String, Value> = KNOWN_SETTINGS
+            .iter()
+            .map(|def| {
+                let value = stored.get(def.key).and_then(|raw| {
+                    def.parse(raw)
+                        .inspect_err(|e| tracing::warn!("Ignoring stored setting: {}", e))
+                        .ok()
+                });
+                let value = value.unwrap_or_else(|| {
+                    def.parse(def.default)
+                        .expect("Default for a setting is not valid")
+                });
+                (def.key.to_string(), value)
+            })
+            .collect();
+
+        serde_json::from_value(Value::Object(values))
+            .expect("KNOWN_SETTINGS does not match RuntimeSettings")
+    }
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        RuntimeSettings::from_stored(&HashMap::new())
+    }
+}
+
+/// The current settings, shared by everything in the server
+#[derive(Debug, Clone)]
+pub struct Settings {
+    db: PgPool,
+    current: Arc<RwLock<Arc<RuntimeSettings>>>,
+}
+
+impl Settings {
+    pub fn new(db: PgPool) -> Self {
+        Settings {
+            db,
+            current: Default::default(),
+        }
+    }
+
+    pub fn get(&self) -> Arc<RuntimeSettings> {
+        self.current.read().expect("Settings lock poisoned").clone()
+    }
+
+    /// Every value in the `config` table, known or not
+    pub async fn stored(&self) -> anyhow::Result<HashMap<String, String>> {
+        let rows = sqlx::query!("SELECT key, value FROM config")
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| (r.key, r.value)).collect())
+    }
+
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let settings = RuntimeSettings::from_stored(&self.stored().await?);
+        let mut current = self.current.write().expect("Settings lock poisoned");
+        if **current != settings {
+            tracing::info!(?settings, "Runtime settings changed");
+            *current = Arc::new(settings);
+        }
+
+        Ok(())
+    }
+
+    /// Stores a value from [`SettingDef::validate`], and reloads
+    pub async fn set(&self, def: &SettingDef, raw: &str) -> anyhow::Result<()> {
+        sqlx::query!(
+            "INSERT INTO config (key, value, description)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE SET value = $2, description = $3",
+            def.key,
+            raw,
+            def.description,
+        )
+        .execute(&self.db)
+        .await?;
+        self.reload().await
+    }
+
+    /// Goes back to the default value, and reloads
+    pub async fn reset(&self, def: &SettingDef) -> anyhow::Result<()> {
+        sqlx::query!("DELETE FROM config WHERE key = $1", def.key)
+            .execute(&self.db)
+            .await?;
+        self.reload().await
+    }
+
+    /// Reloads whenever another server (or someone with psql) changes the `config` table, and
+    /// every minute in case a notification was missed
+    pub async fn listen(self, shutdown: CancellationToken) {
+        while !shutdown.is_cancelled() {
+            if let Err(e) = self.listen_once(&shutdown).await {
+                tracing::warn!("Settings listener failed: {}", e);
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    _ = shutdown.cancelled() => {}
+                }
+            }
+        }
+    }
+
+    async fn listen_once(&self, shutdown: &CancellationToken) -> anyhow::Result<()> {
+        let mut listener = PgListener::connect_with(&self.db).await?;
+        listener.listen("config").await?;
+        // Anything that changed while we weren't listening
+        self.reload().await?;
+
+        loop {
+            tokio::select! {
+                n = listener.recv() => {
+                    n?;
+                }
+                _ = tokio::time::sleep(Duration::from_secs(60)) => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+            self.reload().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let settings = RuntimeSettings::default();
+        assert!(settings.auto_analysis_enabled);
+        assert_eq!(settings.finished_game_check_delay_ms, 1000);
+
+        // Every field has a known setting, and the other way around
+        let fields = serde_json::to_value(&settings).unwrap();
+        assert_eq!(fields.as_object().unwrap().len(), KNOWN_SETTINGS.len());
+    }
+
+    #[test]
+    fn test_stored_values() {
+        let stored = HashMap::from([
+            ("auto_analysis_enabled".to_string(), "false".to_string()),
+            (
+                "finished_game_check_delay_ms".to_string(),
+                "999999".to_string(),
+            ),
+            ("something_else".to_string(), "hello".to_string()),
+        ]);
+        let settings = RuntimeSettings::from_stored(&stored);
+        assert!(!settings.auto_analysis_enabled);
+        // Out of range, so the default is used
+        assert_eq!(settings.finished_game_check_delay_ms, 1000);
+
+        let def = find_setting("finished_game_check_delay_ms").unwrap();
+        let value = |json: &str| serde_json::from_str::<SettingValue>(json).unwrap();
+        assert_eq!(def.validate(&value("250")), Ok("250".to_string()));
+        assert_eq!(def.validate(&value(r#""250""#)), Ok("250".to_string()));
+        assert!(def.validate(&value("-1")).is_err());
+        assert!(def.validate(&value("true")).is_err());
+
+        let def = find_setting("auto_analysis_enabled").unwrap();
+        assert_eq!(def.validate(&value("false")), Ok("false".to_string()));
+        assert!(def.validate(&value(r#""yes""#)).is_err());
+        assert!(find_setting("something_else").is_none());
+    }
+}
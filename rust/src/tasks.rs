@@ -3,12 +3,25 @@
 use anyhow::Context;
 use serde_json;
 use sqlx::PgPool;
+use std::collections::HashMap;
 use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+pub mod auto_analysis;
+pub mod fetch_ledger;
+pub mod leader;
+pub mod mirror;
+pub mod queue_events;
+pub mod registry;
+pub mod schedule;
 
 use crate::{
-    AnalysisQueueStatus, Config,
-    api::openfrontapi::{Lobby, OpenFrontAPI},
+    AnalysisQueueStatus, Config, analysis,
+    api::openfrontapi::{Lobby, OpenFrontAPI, OpenFrontError},
     database::now_unix_sec,
+    game_record::FinishedGame,
+    settings::Settings,
 };
 
 pub async fn get_new_games(ofapi: &impl OpenFrontAPI, _cfg: &Config) -> anyhow::Result<Vec<Lobby>> {
@@ -16,15 +29,34 @@ pub async fn get_new_games(ofapi: &impl OpenFrontAPI, _cfg: &Config) -> anyhow::
     Ok(new_games.lobbies)
 }
 
-#[derive(
-    Debug, Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema, PartialEq, Eq,
-)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub enum GameStatus {
-    Finished(serde_json::Value),
+    Finished(FinishedGame),
     Error(serde_json::Value),
     NotFound,
 }
 
+impl GameStatus {
+    /// What an upstream error says about the game itself. Only a 404 does. Everything else
+    /// (rate limits, challenge pages, outages, garbled bodies, statuses we don't expect) is about
+    /// the response we got, so the game should be checked again later.
+    pub fn from_upstream_error(e: &OpenFrontError) -> Option<GameStatus> {
+        match e {
+            OpenFrontError::NotFound => Some(GameStatus::NotFound),
+            _ => None,
+        }
+    }
+
+    /// The analysis queue status for a job waiting on this game, if it should change
+    pub fn analysis_queue_status(&self) -> Option<AnalysisQueueStatus> {
+        match self {
+            GameStatus::Finished(_) => None,
+            GameStatus::Error(_) => Some(AnalysisQueueStatus::Failed),
+            GameStatus::NotFound => Some(AnalysisQueueStatus::NotFound),
+        }
+    }
+}
+
 pub async fn insert_new_game(first: &Lobby, database: &PgPool) -> anyhow::Result<u64> {
     let player_teams_as_int: i32 = first.game_config.teams().into();
 
@@ -50,14 +82,25 @@ pub async fn insert_new_game(first: &Lobby, database: &PgPool) -> anyhow::Result
         serde_json::to_value(&first.game_config).unwrap()
     ).fetch_one(database).await?;
 
-    let num_players_left = (first.game_config.max_players - first.num_clients).max(0);
+    sqlx::query!(
+        "INSERT INTO
+            lobby_snapshots (game_id, seen_unix_sec, num_clients, ms_until_start)
+        VALUES
+            ($1, $2, $3, $4)
+        ON CONFLICT (game_id, seen_unix_sec)
+        DO UPDATE
+            SET num_clients = $3
+            , ms_until_start = $4
+        ",
+        first.game_id,
+        last_seen_unix_sec,
+        first.num_clients,
+        first.ms_until_start as i64,
+    )
+    .execute(database)
+    .await?;
 
-    // Wait between 3 and 15 seconds before checking again.
-    let next_time = (first.ms_until_start)
-        .min(15500)
-        .min(num_players_left as u64 * 1000)
-        .max(3500)
-        - 500;
+    let next_time = next_lobby_check_ms(first);
 
     if first_seen_res.first_seen_unix_sec == last_seen_unix_sec {
         tracing::info!(
@@ -86,47 +129,128 @@ pub async fn insert_new_game(first: &Lobby, database: &PgPool) -> anyhow::Result
     Ok(next_time)
 }
 
+/// How long to wait before this lobby should be checked again, in ms
+pub fn next_lobby_check_ms(lobby: &Lobby) -> u64 {
+    let num_players_left = (lobby.game_config.max_players - lobby.num_clients).max(0);
+
+    // Wait between 3 and 15 seconds before checking again.
+    (lobby.ms_until_start)
+        .min(15500)
+        .min(num_players_left as u64 * 1000)
+        .max(3500)
+        - 500
+}
+
+/// How long to wait between polls when there are no public lobbies at all
+const NO_LOBBIES_CHECK_MS: u64 = 15000;
+
+/// A lobby can vanish from the public list slightly before its countdown ends
+const LOBBY_START_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// Upserts every lobby and returns how long to wait before the next poll, which is when the
+/// soonest lobby needs to be checked again.
+pub async fn insert_new_lobbies(lobbies: &[Lobby], database: &PgPool) -> anyhow::Result<u64> {
+    let mut next_time = NO_LOBBIES_CHECK_MS;
+    for lobby in lobbies {
+        next_time = next_time.min(insert_new_game(lobby, database).await?);
+    }
+
+    Ok(next_time)
+}
+
+/// A lobby that was in the last poll but is no longer listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisappearedLobby {
+    pub game_id: String,
+    /// The lobby left the list before its countdown ended, so it must have filled up.
+    pub filled_early: bool,
+}
+
+/// Compares the lobbies we were tracking (game id -> expected start time) to the ones that are
+/// listed now.
+pub fn find_disappeared_lobbies(
+    tracked: &HashMap<String, Instant>,
+    current: &[Lobby],
+    now: Instant,
+) -> Vec<DisappearedLobby> {
+    let mut gone: Vec<_> = tracked
+        .iter()
+        .filter(|(game_id, _)| !current.iter().any(|l| &l.game_id == *game_id))
+        .map(|(game_id, expected_start)| DisappearedLobby {
+            game_id: game_id.clone(),
+            filled_early: now + LOBBY_START_TOLERANCE < *expected_start,
+        })
+        .collect();
+    gone.sort_by(|a, b| a.game_id.cmp(&b.game_id));
+    gone
+}
+
 pub async fn look_for_new_games(
     ofapi: impl OpenFrontAPI,
     database: PgPool,
     cfg: std::sync::Arc<Config>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<()> {
-    let mut expected_to_be_new_game_next_check = true;
-    let mut last_game_id = String::new();
-    loop {
+    // Every lobby from the last poll, and when it was expected to start.
+    let mut tracked: HashMap<String, Instant> = HashMap::new();
+    while !shutdown.is_cancelled() {
         let new_games = get_new_games(&ofapi, &*cfg).await?;
-        let first = new_games.first().context("No new games found...")?;
+        let now = Instant::now();
 
-        if first.game_id != last_game_id {
+        for lobby in find_disappeared_lobbies(&tracked, &new_games, now) {
             tracing::info!(
-                expected_to_be_new_game_next_check,
-                "New game found: {}",
-                first.game_id
+                filled_early = lobby.filled_early,
+                "Lobby {} is no longer listed",
+                lobby.game_id
             );
-            if !expected_to_be_new_game_next_check {
-                // We got a new game earlier than expected. The last one must have been full.
+            if lobby.filled_early {
+                // The lobby went away before its countdown ended. It must have been full.
                 sqlx::query!(
                     "UPDATE lobbies SET approx_num_players = max_players WHERE game_id = $1",
-                    last_game_id
+                    lobby.game_id
                 )
                 .execute(&database)
                 .await?;
             }
-            last_game_id = first.game_id.clone();
         }
 
-        let next_time = insert_new_game(first, &database).await?;
+        if new_games.is_empty() {
+            tracing::warn!("No public lobbies found...");
+        }
+
+        let next_time = insert_new_lobbies(&new_games, &database).await?;
 
-        expected_to_be_new_game_next_check = next_time > first.ms_until_start;
-        tokio::time::sleep(tokio::time::Duration::from_millis(next_time)).await;
+        tracked = new_games
+            .iter()
+            .map(|l| {
+                let expected_start = now + Duration::from_millis(l.ms_until_start);
+                (l.game_id.clone(), expected_start)
+            })
+            .collect();
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(next_time)) => {}
+            _ = shutdown.cancelled() => {}
+        }
     }
+
+    Ok(())
 }
 
 pub async fn check_if_game_finished(
     ofapi: impl OpenFrontAPI,
     game_id: &str,
 ) -> anyhow::Result<GameStatus> {
-    let finished = ofapi.get_game_json(game_id).await?;
+    let finished = match ofapi.get_game_json(game_id).await {
+        Ok(finished) => finished,
+        Err(e) => match GameStatus::from_upstream_error(&e) {
+            Some(status) => return Ok(status),
+            None => {
+                return Err(anyhow::Error::new(e))
+                    .with_context(|| format!("Failed to fetch game {}", game_id));
+            }
+        },
+    };
 
     if finished.get("error").is_some() {
         if finished["error"] == "Not found" {
@@ -138,16 +262,24 @@ pub async fn check_if_game_finished(
 
     if finished.get("gitCommit").is_some() {
         // Game is finished!
-        let winning_id = finished["info"]["winner"][1].as_str();
+        let game = match FinishedGame::from_value(finished.clone()) {
+            Ok(game) => game,
+            Err(e) => {
+                // Keep the raw json around so it can be parsed again once the model is fixed.
+                tracing::error!(game_id, "Finished game has an invalid game record: {}", e);
+                return Ok(GameStatus::Error(finished));
+            }
+        };
+
+        let winner = game.record.winning_player();
+        let winning_id = winner.map(|p| p.client_id.as_str());
         tracing::info!(winning_id, game_id, "Game is finished.");
 
-        for player in finished["info"]["players"].as_array().unwrap() {
-            if player["clientID"].as_str() == winning_id {
-                tracing::info!("Winning player: {}", player["username"]);
-            }
+        if let Some(player) = winner {
+            tracing::info!("Winning player: {}", player.username);
         }
 
-        return Ok(GameStatus::Finished(finished));
+        return Ok(GameStatus::Finished(game));
     }
 
     tracing::error!("Game {} is in an unknown other state.", game_id);
@@ -161,8 +293,8 @@ pub async fn save_finished_game(
     game_id: &str,
 ) -> anyhow::Result<()> {
     let (result_json, is_ok) = match status {
-        GameStatus::Finished(json) => (json, true),
-        GameStatus::Error(json) => (json, false),
+        GameStatus::Finished(game) => (game.raw.clone(), true),
+        GameStatus::Error(json) => (json.clone(), false),
         GameStatus::NotFound => {
             tracing::info!("Game {} not found, skipping.", game_id);
             return Ok(());
@@ -177,8 +309,10 @@ pub async fn save_finished_game(
     .execute(&mut *txn)
     .await?;
 
-    sqlx::query!(
-        "INSERT INTO finished_games (game_id, result_json, is_ok) VALUES ($1, $2, $3)",
+    // Another task may have saved this game since we checked
+    let res = sqlx::query!(
+        "INSERT INTO finished_games (game_id, result_json, is_ok) VALUES ($1, $2, $3)
+        ON CONFLICT (game_id) DO NOTHING",
         game_id,
         result_json,
         is_ok
@@ -188,8 +322,25 @@ pub async fn save_finished_game(
 
     txn.commit().await?;
 
-    let dur_secs = result_json["info"]["duration"].as_i64().unwrap_or(0);
-    let num_turns = result_json["info"]["num_turns"].as_i64().unwrap_or(0);
+    if res.rows_affected() == 0 {
+        tracing::info!("Game {} was already saved, skipping.", game_id);
+        return Ok(());
+    }
+
+    let (dur_secs, num_turns) = match status {
+        GameStatus::Finished(FinishedGame { record, .. }) => {
+            // Basic analysis that doesn't need to wait for the simulator
+            let intents = analysis::intents::analyze_intents(record);
+            if let Err(e) =
+                analysis::intents::save_intent_analysis(&database, game_id, &intents).await
+            {
+                tracing::error!(game_id, "Failed to save intent analysis: {}", e);
+            }
+
+            (record.info.duration, record.info.num_turns)
+        }
+        _ => (0, 0),
+    };
     tracing::info!(
         dur_secs,
         num_turns,
@@ -203,16 +354,23 @@ pub async fn save_finished_game(
 pub async fn look_for_lobby_games(
     ofapi: impl OpenFrontAPI,
     database: PgPool,
-    _cfg: std::sync::Arc<Config>,
+    cfg: std::sync::Arc<Config>,
+    settings: Settings,
 ) -> anyhow::Result<()> {
     let unfinished_games = sqlx::query!(
         "SELECT
-            game_id
-        FROM lobbies
+            lo.game_id
+        FROM lobbies lo
+            LEFT JOIN game_fetch_ledger gl
+            ON lo.game_id = gl.game_id
         WHERE
-            completed = false
-            AND last_seen_unix_sec < extract(epoch from (NOW() - INTERVAL '15 minutes'))
-            -- AND last_seen_unix_sec > extract(epoch from (NOW() - INTERVAL '2 hours'))
+            lo.completed = false
+            AND lo.last_seen_unix_sec < extract(epoch from (NOW() - INTERVAL '15 minutes'))
+            AND (
+                gl.game_id IS NULL
+                OR (NOT gl.abandoned AND gl.next_check_unix_sec <= extract(epoch from NOW()))
+            )
+        ORDER BY lo.last_seen_unix_sec ASC
         "
     )
     .fetch_all(&database)
@@ -225,47 +383,65 @@ pub async fn look_for_lobby_games(
 
     for game in unfinished_games {
         let game_id = &game.game_id;
-        let finish_status = check_if_game_finished(&ofapi, game_id).await?;
-        save_finished_game(database.clone(), &finish_status, game_id).await?;
-
-        let should_auto_analyze =
-            sqlx::query!("SELECT key, value FROM config WHERE key = 'auto_analyze_games'")
-                .fetch_optional(&database)
-                .await?
-                .map(|row| row.value == "true")
-                .unwrap_or(false);
-
-        if should_auto_analyze && matches!(finish_status, GameStatus::Finished(_)) {
-            let new_pool = database.clone();
-            let game_id = game.game_id.clone();
-            tokio::task::spawn(async move {
-                tokio::time::sleep(Duration::from_secs(10)).await;
-                let Ok(res) = sqlx::query!(
-                    "INSERT INTO analysis_queue (game_id, requesting_user_id)
-                     VALUES ($1, NULL)",
+        let finish_status = match check_and_save_game(&ofapi, &database, game_id).await {
+            Ok(status) => status,
+            Err(e) if fetch_ledger::should_stop_batch(&e) => {
+                // Every other game would fail the same way, so try again next run.
+                return Err(e).context("Stopped checking for finished games");
+            }
+            Err(e) => {
+                tracing::error!(game_id, "Failed to check game {}: {:?}", game_id, e);
+                fetch_ledger::record_failure(
+                    &database,
+                    &cfg,
                     game_id,
+                    fetch_ledger::GameFetchOutcome::FetchError,
+                    Some(format!("{:#}", e)),
                 )
-                .execute(&new_pool)
-                .await
-                .context("Failed to insert new game into analysis queue") else {
-                    tracing::error!("Failed to insert game {} into analysis queue.", game_id);
-                    return;
-                };
-
-                if res.rows_affected() > 0 {
-                    tracing::warn!("Game {} added to analysis queue.", game_id);
-                } else {
-                    tracing::info!("Game {} already in analysis queue.", game_id);
-                }
-            });
+                .await?;
+                continue;
+            }
+        };
+
+        if matches!(finish_status, GameStatus::NotFound) {
+            fetch_ledger::record_failure(
+                &database,
+                &cfg,
+                game_id,
+                fetch_ledger::GameFetchOutcome::NotFound,
+                None,
+            )
+            .await?;
+        } else {
+            fetch_ledger::clear(&database, game_id).await?;
         }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        let current = settings.get();
+        if current.auto_analysis_enabled && matches!(finish_status, GameStatus::Finished(_)) {
+            if let Err(e) = auto_analysis::auto_analyze(&database, game_id).await {
+                tracing::error!(game_id, "Failed to run auto analysis rules: {:?}", e);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(
+            current.finished_game_check_delay_ms as u64,
+        ))
+        .await;
     }
 
     Ok(())
 }
 
+async fn check_and_save_game(
+    ofapi: &impl OpenFrontAPI,
+    database: &PgPool,
+    game_id: &str,
+) -> anyhow::Result<GameStatus> {
+    let finish_status = check_if_game_finished(ofapi, game_id).await?;
+    save_finished_game(database.clone(), &finish_status, game_id).await?;
+    Ok(finish_status)
+}
+
 #[derive(Debug, Clone)]
 #[allow(unused)]
 pub enum BackoffStrategy {
@@ -280,6 +456,9 @@ pub enum BackoffStrategy {
         increment: Duration,
         max_stacks: usize,
     },
+    /// Doubles from `base` up to `max`, then picks a random wait between half of that and all
+    /// of it, so tasks that failed together don't all retry together.
+    Jittered { base: Duration, max: Duration },
 }
 
 impl BackoffStrategy {
@@ -303,14 +482,26 @@ impl BackoffStrategy {
                 let stacks = current.min(max_stacks);
                 start + increment * stacks as u32
             }
+            BackoffStrategy::Jittered { base, max } => {
+                let cap = base
+                    .saturating_mul(2u32.saturating_pow(current.min(32) as u32))
+                    .min(max);
+                cap / 2 + schedule::jitter(cap / 2)
+            }
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct TaskSettings {
-    pub sleep_time: Duration,
+    pub schedule: schedule::Schedule,
+    /// Add a random wait of up to this much before each scheduled run
+    pub jitter: Duration,
+    /// Cancel a run that takes longer than this, and count it as a failure
+    pub timeout: Option<Duration>,
     pub backoff_strategy: BackoffStrategy,
+    /// Only run while holding this lock, so one replica runs the task at a time
+    pub leader: Option<leader::LeaderLock>,
 }
 
 impl Default for BackoffStrategy {
@@ -326,30 +517,83 @@ impl Default for BackoffStrategy {
 impl Default for TaskSettings {
     fn default() -> Self {
         TaskSettings {
-            sleep_time: Duration::from_secs(60), // 1 minute
+            schedule: schedule::Schedule::Every(Duration::from_secs(60)), // 1 minute
+            jitter: Duration::ZERO,
+            timeout: None,
             backoff_strategy: BackoffStrategy::default(),
+            leader: None,
         }
     }
 }
 
-pub fn keep_task_alive<F, R>(mut task: F, task_settings: TaskSettings)
+pub fn keep_task_alive<F, R>(handle: registry::TaskHandle, mut task: F, task_settings: TaskSettings)
 where
     F: FnMut() -> R + Send + 'static,
     R: std::future::Future<Output = anyhow::Result<()>> + Send,
 {
-    tokio::spawn(async move {
+    let task_settings = handle.configure(task_settings);
+    handle.clone().spawn(async move {
         let mut backoff = 0;
-        loop {
-            if let Err(e) = task().await {
+        let mut wait = task_settings.schedule.first_wait() + schedule::jitter(task_settings.jitter);
+        while handle.wait_for_next_run(wait).await {
+            if let Some(leader) = &task_settings.leader {
+                match leader.acquire().await {
+                    Ok(true) => handle.set_standby(false),
+                    Ok(false) => {
+                        handle.set_standby(true);
+                        wait = leader.retry;
+                        continue;
+                    }
+                    Err(e) => {
+                        tracing::warn!(task = ?handle.status().task, "Failed to check leader lock: {}", e);
+                        wait = leader.retry;
+                        continue;
+                    }
+                }
+            }
+
+            // A run is never interrupted by shutdown, so shutdown waits for it to finish. It is
+            // interrupted if it times out, or if we stop being the leader since another replica
+            // will take over.
+            handle.started();
+            let run = async {
+                match task_settings.timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, task())
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(anyhow::anyhow!("Timed out after {:?}", timeout))
+                        }),
+                    None => task().await,
+                }
+            };
+            let res = match &task_settings.leader {
+                Some(leader) => tokio::select! {
+                    res = run => res,
+                    e = leader.lost() => Err(e),
+                },
+                None => run.await,
+            };
+            wait = task_settings.schedule.next_wait() + schedule::jitter(task_settings.jitter);
+            if let Err(e) = res {
+                handle.failed(&e);
                 let next_backoff_dur = task_settings.backoff_strategy.next_backoff(backoff);
-                tracing::error!(wait_sec = next_backoff_dur.as_secs(), "Task failed: {}", e);
-                tokio::time::sleep(next_backoff_dur).await;
+                tracing::error!(
+                    task = ?handle.status().task,
+                    wait_sec = next_backoff_dur.as_secs(),
+                    "Task failed: {}",
+                    e
+                );
+                wait += next_backoff_dur;
                 backoff += 1;
             } else {
+                handle.succeeded();
                 backoff = 0;
             }
-            tokio::time::sleep(task_settings.sleep_time).await;
         }
+        if let Some(leader) = &task_settings.leader {
+            leader.release().await;
+        }
+        tracing::info!(task = ?handle.status().task, "Task stopped");
     });
 }
 
@@ -378,13 +622,15 @@ pub async fn look_for_new_game_in_analysis_queue(
             aq.game_id, aq.requesting_user_id
         FROM
             analysis_queue aq
+            JOIN analysis_queue_order o
+            ON aq.game_id = o.game_id
             LEFT JOIN finished_games fg
             ON aq.game_id = fg.game_id
         WHERE
             fg.game_id IS NULL
             AND aq.status = 'Pending'
         ORDER BY
-            aq.requested_unix_sec ASC
+            o.position ASC
         LIMIT 1
         "#
     )
@@ -397,15 +643,11 @@ pub async fn look_for_new_game_in_analysis_queue(
 
     let result_maybe = check_if_game_finished(ofapi, &game.game_id).await?;
     // Maybe update the analysis queue.
-    let maybe_new_db_status = match result_maybe {
-        GameStatus::Finished(_) => None,
-        GameStatus::Error(_) => Some(AnalysisQueueStatus::Failed),
-        GameStatus::NotFound => Some(AnalysisQueueStatus::NotFound),
-    };
+    let maybe_new_db_status = result_maybe.analysis_queue_status();
 
     if let Some(new_db_status) = maybe_new_db_status {
         sqlx::query!(
-            "UPDATE analysis_queue SET status = $2 WHERE game_id = $1",
+            "UPDATE analysis_queue SET status = $2 WHERE game_id = $1 AND status = 'Pending'",
             game.game_id,
             new_db_status as AnalysisQueueStatus,
         )
@@ -423,6 +665,33 @@ pub async fn look_for_old_running_games(
     db: PgPool,
     _cfg: std::sync::Arc<Config>,
 ) -> anyhow::Result<()> {
+    // A worker that stopped sending heartbeats has most likely died, so let another one try
+    let requeued = sqlx::query_scalar!(
+        r#"
+        UPDATE
+            analysis_queue
+        SET
+            status = 'Pending',
+            worker_id = NULL,
+            lease_expires_unix_sec = NULL,
+            started_unix_sec = NULL
+        WHERE
+            status = 'Running'
+            AND lease_expires_unix_sec < extract(epoch from NOW())
+        RETURNING game_id
+        "#
+    )
+    .fetch_all(&db)
+    .await?;
+    if !requeued.is_empty() {
+        tracing::warn!(
+            "Worker leases expired, requeued {} analysis jobs: {:?}",
+            requeued.len(),
+            requeued
+        );
+    }
+
+    // Runs without a lease are not from a worker, so there is nothing to renew them
     let res = sqlx::query!(
         r#"
         UPDATE
@@ -432,6 +701,7 @@ pub async fn look_for_old_running_games(
         WHERE
             started_unix_sec < extract(epoch from (NOW() - INTERVAL '60 minutes'))
             AND status = 'Running'
+            AND lease_expires_unix_sec IS NULL
         "#
     )
     .execute(&db)
@@ -442,6 +712,42 @@ pub async fn look_for_old_running_games(
     Ok(())
 }
 
+pub async fn look_for_old_sessions(db: PgPool) -> anyhow::Result<()> {
+    let res = sqlx::query!(
+        r#"
+        DELETE FROM
+            social.user_sessions
+        WHERE
+            expires_at_unix_sec < extract(epoch from NOW())
+        "#
+    )
+    .execute(&db)
+    .await?;
+    tracing::info!("Deleted {} expired sessions.", res.rows_affected());
+
+    Ok(())
+}
+
+/// The games in a player's data from the OpenFront API, as `(game_id, client_id)`
+fn player_games(dat: &serde_json::Value) -> anyhow::Result<Vec<(&str, &str)>> {
+    let games = dat["games"]
+        .as_array()
+        .context("Player data has no list of games")?;
+
+    games
+        .iter()
+        .map(|game| {
+            let game_id = game["gameId"]
+                .as_str()
+                .context("Player game has no gameId")?;
+            let client_id = game["clientId"]
+                .as_str()
+                .with_context(|| format!("Player game {} has no clientId", game_id))?;
+            Ok((game_id, client_id))
+        })
+        .collect()
+}
+
 pub async fn update_players_tracked_games(
     db: PgPool,
     openfront_player_id: &str,
@@ -449,10 +755,46 @@ pub async fn update_players_tracked_games(
 ) -> anyhow::Result<()> {
     let dat = ofapi.get_player_data(openfront_player_id).await?;
 
-    for game in dat["games"].as_array().unwrap() {
-        let game_id = game["gameId"].as_str().unwrap();
-        let client_id = game["clientId"].as_str().unwrap();
-        let new_row = sqlx::query!(
+    // The analysis is for the user who tracks this player, if they registered
+    let requesting_user_id = sqlx::query_scalar!(
+        "SELECT id FROM social.registered_users WHERE openfront_player_id = $1",
+        openfront_player_id
+    )
+    .fetch_optional(&db)
+    .await?;
+
+    for (game_id, client_id) in player_games(&dat)? {
+        let already_tracked = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM social.tracked_player_in_game
+                WHERE openfront_player_id = $1 AND game_id = $2 AND client_id = $3
+            ) AS "exists!"
+            "#,
+            openfront_player_id,
+            game_id,
+            client_id,
+        )
+        .fetch_one(&db)
+        .await?;
+        if already_tracked {
+            continue;
+        }
+
+        // A game is only tracked once it has been fetched, so one that fails is tried again the
+        // next time the player is checked
+        match fetch_tracked_game(&db, ofapi, game_id, requesting_user_id.as_deref()).await {
+            Ok(()) => {}
+            Err(e) if fetch_ledger::should_stop_batch(&e) => {
+                return Err(e).context("Stopped fetching tracked games");
+            }
+            Err(e) => {
+                tracing::error!(game_id, "Failed to fetch tracked game: {:?}", e);
+                continue;
+            }
+        }
+
+        sqlx::query!(
             r#"
             INSERT INTO social.tracked_player_in_game (
                 openfront_player_id, game_id, client_id
@@ -465,15 +807,49 @@ pub async fn update_players_tracked_games(
         )
         .execute(&db)
         .await?;
+        tracing::info!(
+            "Inserted player {} with game {} as client ID {}",
+            openfront_player_id,
+            game_id,
+            client_id
+        );
 
-        if new_row.rows_affected() > 0 {
-            tracing::info!(
-                "Inserted player {} with game {} as client ID {}",
-                openfront_player_id,
-                game_id,
-                client_id
-            );
-        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    Ok(())
+}
+
+/// Downloads a game a tracked player was in, and queues it for analysis. Games we already have
+/// are left alone.
+async fn fetch_tracked_game(
+    db: &PgPool,
+    ofapi: &impl OpenFrontAPI,
+    game_id: &str,
+    requesting_user_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let already_saved = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM finished_games WHERE game_id = $1) AS "exists!""#,
+        game_id
+    )
+    .fetch_one(db)
+    .await?;
+    if already_saved {
+        tracing::info!(game_id, "Tracked game already saved, skipping.");
+        return Ok(());
+    }
+
+    let status = check_if_game_finished(ofapi, game_id).await?;
+    save_finished_game(db.clone(), &status, game_id).await?;
+
+    if matches!(status, GameStatus::Finished(_))
+        && crate::import::enqueue_analysis(db, game_id, requesting_user_id, 0).await?
+    {
+        tracing::info!(
+            game_id,
+            requesting_user_id,
+            "Tracked game added to analysis queue."
+        );
     }
 
     Ok(())
@@ -482,6 +858,7 @@ pub async fn update_players_tracked_games(
 pub async fn look_for_tracked_player_games(
     db: PgPool,
     ofapi: impl OpenFrontAPI,
+    settings: Settings,
 ) -> anyhow::Result<()> {
     tracing::info!("Looking for tracked players to update their games...");
     let res = sqlx::query!(
@@ -491,10 +868,11 @@ pub async fn look_for_tracked_player_games(
         SET
             last_check_unix_sec = EXTRACT(EPOCH FROM NOW())
         WHERE
-            last_check_unix_sec < extract(epoch from (NOW() - INTERVAL '30 minutes'))
+            last_check_unix_sec < extract(epoch from NOW()) - $1::BIGINT
             AND is_tracking = true
         RETURNING openfront_player_id
-        "#
+        "#,
+        settings.get().tracked_player_check_interval_secs,
     )
     .fetch_all(&db)
     .await?;
@@ -514,3 +892,273 @@ pub async fn look_for_tracked_player_games(
     tracing::info!("Finished updating tracked players' games.");
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::openfrontapi::MockOpenFrontAPI;
+
+    fn mock_game_response(res: Result<serde_json::Value, OpenFrontError>) -> MockOpenFrontAPI {
+        let mut ofapi = MockOpenFrontAPI::new();
+        ofapi.expect_get_game_json().returning(move |_| {
+            let res = res.clone();
+            Box::pin(async move { res })
+        });
+        ofapi
+    }
+
+    #[tokio::test]
+    async fn test_check_if_game_finished_upstream_errors() {
+        let not_found = check_if_game_finished(
+            mock_game_response(Err(OpenFrontError::NotFound)),
+            "abcd1234",
+        )
+        .await
+        .unwrap();
+        assert!(matches!(not_found, GameStatus::NotFound));
+        assert_eq!(
+            not_found.analysis_queue_status(),
+            Some(AnalysisQueueStatus::NotFound)
+        );
+
+        let body = serde_json::json!({"error": "Not found"});
+        let not_found = check_if_game_finished(mock_game_response(Ok(body)), "abcd1234")
+            .await
+            .unwrap();
+        assert!(matches!(not_found, GameStatus::NotFound));
+
+        // These are problems with the upstream, not the game, so they must not be saved as a
+        // game error or change the queue status.
+        for e in [
+            OpenFrontError::ChallengePage,
+            OpenFrontError::RateLimited {
+                retry_after: Some(Duration::from_secs(30)),
+            },
+            OpenFrontError::Upstream5xx {
+                status: 503,
+                retry_after: Some(Duration::from_secs(30)),
+            },
+            OpenFrontError::MalformedBody("expected value".into()),
+            OpenFrontError::Timeout,
+            OpenFrontError::Network("connection reset".into()),
+        ] {
+            let err = check_if_game_finished(mock_game_response(Err(e.clone())), "abcd1234")
+                .await
+                .unwrap_err();
+            assert_eq!(err.downcast_ref::<OpenFrontError>(), Some(&e));
+            assert!(e.is_transient());
+        }
+
+        // A status we don't know about could be anything, so it is retried through the ledger
+        let unexpected = OpenFrontError::UnexpectedStatus {
+            status: 418,
+            body: "teapot".into(),
+        };
+        assert!(GameStatus::from_upstream_error(&unexpected).is_none());
+        let err = check_if_game_finished(mock_game_response(Err(unexpected.clone())), "abcd1234")
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<OpenFrontError>(), Some(&unexpected));
+    }
+
+    fn lobby(game_id: &str, num_clients: i32, ms_until_start: u64) -> Lobby {
+        serde_json::from_value(serde_json::json!({
+            "gameID": game_id,
+            "numClients": num_clients,
+            "msUntilStart": ms_until_start,
+            "gameConfig": {
+                "gameMap": "World",
+                "gameType": "Public",
+                "difficulty": "Medium",
+                "disableNPCs": false,
+                "infiniteGold": false,
+                "infiniteTroops": false,
+                "instantBuild": false,
+                "gameMode": "Free For All",
+                "bots": 400,
+                "disabledUnits": [],
+                "maxPlayers": 50
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_next_lobby_check_ms() {
+        assert_eq!(next_lobby_check_ms(&lobby("aaaaaaaa", 1, 60_000)), 15000);
+        assert_eq!(next_lobby_check_ms(&lobby("aaaaaaaa", 1, 5_000)), 4500);
+        assert_eq!(next_lobby_check_ms(&lobby("aaaaaaaa", 1, 100)), 3000);
+        // Nearly full lobbies are checked sooner
+        assert_eq!(next_lobby_check_ms(&lobby("aaaaaaaa", 46, 60_000)), 3500);
+    }
+
+    #[test]
+    fn test_find_disappeared_lobbies() {
+        let now = Instant::now();
+        let tracked: HashMap<String, Instant> = [
+            ("stillhere", now + Duration::from_secs(30)),
+            ("fullgame", now + Duration::from_secs(30)),
+            ("started1", now - Duration::from_secs(2)),
+            ("started2", now + Duration::from_millis(200)),
+        ]
+        .into_iter()
+        .map(|(id, t)| (id.to_string(), t))
+        .collect();
+
+        let current = vec![lobby("stillhere", 10, 25_000), lobby("newlobby", 1, 60_000)];
+
+        let gone = find_disappeared_lobbies(&tracked, &current, now);
+        assert_eq!(
+            gone,
+            vec![
+                DisappearedLobby {
+                    game_id: "fullgame".into(),
+                    filled_early: true,
+                },
+                DisappearedLobby {
+                    game_id: "started1".into(),
+                    filled_early: false,
+                },
+                DisappearedLobby {
+                    game_id: "started2".into(),
+                    filled_early: false,
+                },
+            ]
+        );
+
+        let next = current.iter().map(next_lobby_check_ms).min();
+        assert_eq!(next, Some(15000));
+    }
+
+    #[tokio::test]
+    async fn test_check_if_game_finished_parses_record() {
+        let json = crate::utils::load_game_in_test("mygame").unwrap();
+        let status = check_if_game_finished(mock_game_response(Ok(json)), "abcd1234")
+            .await
+            .unwrap();
+        assert!(matches!(status, GameStatus::Finished(_)));
+        assert_eq!(status.analysis_queue_status(), None);
+    }
+
+    #[tokio::test]
+    async fn test_finished_games_are_stored_as_sent() {
+        for (name, contents) in crate::utils::all_games_in_test() {
+            let Ok(json) = serde_json::from_slice::<serde_json::Value>(contents) else {
+                continue;
+            };
+            let status = check_if_game_finished(mock_game_response(Ok(json.clone())), "abcd1234")
+                .await
+                .unwrap();
+
+            let stored = match status {
+                GameStatus::Finished(game) => game.raw,
+                GameStatus::Error(json) => json,
+                GameStatus::NotFound => continue,
+            };
+            assert_eq!(stored, json, "{name} changed before being stored");
+        }
+    }
+
+    #[test]
+    fn test_jittered_backoff() {
+        let backoff = BackoffStrategy::Jittered {
+            base: Duration::from_secs(4),
+            max: Duration::from_secs(60),
+        };
+        for _ in 0..50 {
+            let first = backoff.next_backoff(0);
+            assert!(first >= Duration::from_secs(2) && first <= Duration::from_secs(4));
+            let third = backoff.next_backoff(2);
+            assert!(third >= Duration::from_secs(8) && third <= Duration::from_secs(16));
+            let capped = backoff.next_backoff(100);
+            assert!(capped >= Duration::from_secs(30) && capped <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn test_player_games() {
+        let dat = serde_json::json!({
+            "games": [{ "gameId": "game0001", "clientId": "client01", "type": "Public" }]
+        });
+        assert_eq!(player_games(&dat).unwrap(), vec![("game0001", "client01")]);
+
+        assert!(player_games(&serde_json::json!({})).is_err());
+        let no_client = serde_json::json!({ "games": [{ "gameId": "game0001" }] });
+        assert!(player_games(&no_client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_tracked_games_are_retried() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        };
+
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        sqlx::query("INSERT INTO social.tracked_openfront_players (openfront_player_id) VALUES ('player01')")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        // game0001 is fine, game0002 fails with a 502 the first time it is asked for
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut ofapi = MockOpenFrontAPI::new();
+        ofapi.expect_get_player_data().returning(|_| {
+            Box::pin(async {
+                Ok(serde_json::json!({ "games": [
+                    { "gameId": "game0001", "clientId": "client01" },
+                    { "gameId": "game0002", "clientId": "client01" },
+                ]}))
+            })
+        });
+        let counter = calls.clone();
+        ofapi.expect_get_game_json().returning(move |game_id| {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            let res = if game_id == "game0002" && call == 1 {
+                Err(OpenFrontError::Upstream5xx {
+                    status: 502,
+                    retry_after: None,
+                })
+            } else {
+                Ok(crate::utils::load_game_in_test("mygame").unwrap())
+            };
+            Box::pin(async move { res })
+        });
+
+        let tracked = || async {
+            sqlx::query_scalar::<_, String>(
+                "SELECT game_id FROM social.tracked_player_in_game ORDER BY game_id",
+            )
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+        };
+
+        update_players_tracked_games(db.pool.clone(), "player01", &ofapi)
+            .await
+            .unwrap();
+        assert_eq!(tracked().await, ["game0001"]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Only the game that failed is fetched again
+        update_players_tracked_games(db.pool.clone(), "player01", &ofapi)
+            .await
+            .unwrap();
+        assert_eq!(tracked().await, ["game0001", "game0002"]);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let saved: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM finished_games")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(saved, 2);
+
+        // Saving a game twice is not an error
+        let status = check_if_game_finished(&ofapi, "game0001").await.unwrap();
+        save_finished_game(db.pool.clone(), &status, "game0001")
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+//! Decides which finished games are queued for analysis without anyone asking, using the rules
+//! in `analysis_rules`.
+
+use schemars::JsonSchema;
+use sqlx::PgPool;
+
+/// A rule as stored in the database
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct AnalysisRule {
+    pub rule_id: i32,
+    pub created_at_unix_sec: i64,
+    #[serde(flatten)]
+    pub rule: NewAnalysisRule,
+}
+
+/// A rule as sent by an admin. Conditions left out match every game.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct NewAnalysisRule {
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Rules are checked from lowest to highest, and the first match decides
+    #[serde(default)]
+    pub rule_order: i32,
+    pub game_map: Option<String>,
+    pub game_mode: Option<String>,
+    /// 0 for FFA, n for n teams, -n for parties of n
+    pub teams: Option<i32>,
+    pub min_players: Option<i32>,
+    pub max_players: Option<i32>,
+    pub min_duration_sec: Option<i64>,
+    pub max_duration_sec: Option<i64>,
+    /// Only match games that one of our tracked players was in
+    #[serde(default)]
+    pub requires_tracked_player: bool,
+    /// False makes this a rule for games we don't want analyzed
+    #[serde(default = "default_true")]
+    pub enqueue: bool,
+    /// Higher priority games are analyzed first. Games people ask for are queued at
+    /// [`crate::import::REQUESTED_PRIORITY`].
+    #[serde(default)]
+    pub priority: i32,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// What the rules look at
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct GameFacts {
+    pub game_id: String,
+    pub game_map: String,
+    pub game_mode: Option<String>,
+    pub teams: i32,
+    /// Unknown if the game record was moved out of the database
+    pub num_players: Option<i32>,
+    pub duration_sec: Option<i64>,
+    pub has_tracked_player: bool,
+}
+
+/// What happens to a game
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct Decision {
+    /// The first rule that matched, if any
+    pub rule_id: Option<i32>,
+    pub rule_name: Option<String>,
+    pub enqueue: bool,
+    pub priority: i32,
+}
+
+fn within<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    if min.is_none() && max.is_none() {
+        return true;
+    }
+
+    // A limit can't be checked against something we don't know
+    let Some(value) = value else {
+        return false;
+    };
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+impl NewAnalysisRule {
+    pub fn matches(&self, game: &GameFacts) -> bool {
+        self.game_map
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(&game.game_map))
+            && self.game_mode.as_ref().is_none_or(|m| {
+                game.game_mode
+                    .as_ref()
+                    .is_some_and(|g| m.eq_ignore_ascii_case(g))
+            })
+            && self.teams.is_none_or(|t| t == game.teams)
+            && within(game.num_players, self.min_players, self.max_players)
+            && within(
+                game.duration_sec,
+                self.min_duration_sec,
+                self.max_duration_sec,
+            )
+            && (!self.requires_tracked_player || game.has_tracked_player)
+    }
+}
+
+/// Applies the first enabled rule that matches. Games no rule matches are not analyzed.
+pub fn decide<'a>(
+    rules: impl IntoIterator<Item = (Option<i32>, &'a NewAnalysisRule)>,
+    game: &GameFacts,
+) -> Decision {
+    let mut rules: Vec<_> = rules.into_iter().filter(|(_, r)| r.enabled).collect();
+    rules.sort_by_key(|(id, r)| (r.rule_order, *id));
+
+    match rules.into_iter().find(|(_, r)| r.matches(game)) {
+        Some((rule_id, rule)) => Decision {
+            rule_id,
+            rule_name: Some(rule.name.clone()),
+            enqueue: rule.enqueue,
+            priority: rule.priority,
+        },
+        None => Decision {
+            rule_id: None,
+            rule_name: None,
+            enqueue: false,
+            priority: 0,
+        },
+    }
+}
+
+pub async fn load_rules(db: &PgPool) -> anyhow::Result<Vec<AnalysisRule>> {
+    let rules = sqlx::query!(
+        r#"
+        SELECT
+            rule_id, created_at_unix_sec,
+            name, enabled, rule_order, game_map, game_mode, teams,
+            min_players, max_players, min_duration_sec, max_duration_sec,
+            requires_tracked_player, enqueue, priority
+        FROM analysis_rules
+        ORDER BY rule_order, rule_id
+        "#
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|r| AnalysisRule {
+        rule_id: r.rule_id,
+        created_at_unix_sec: r.created_at_unix_sec,
+        rule: NewAnalysisRule {
+            name: r.name,
+            enabled: r.enabled,
+            rule_order: r.rule_order,
+            game_map: r.game_map,
+            game_mode: r.game_mode,
+            teams: r.teams,
+            min_players: r.min_players,
+            max_players: r.max_players,
+            min_duration_sec: r.min_duration_sec,
+            max_duration_sec: r.max_duration_sec,
+            requires_tracked_player: r.requires_tracked_player,
+            enqueue: r.enqueue,
+            priority: r.priority,
+        },
+    })
+    .collect();
+
+    Ok(rules)
+}
+
+/// Returns the id of the new rule
+pub async fn insert_rule(db: &PgPool, rule: &NewAnalysisRule) -> anyhow::Result<i32> {
+    let rule_id = sqlx::query_scalar!(
+        "INSERT INTO analysis_rules (
+            name, enabled, rule_order, game_map, game_mode, teams,
+            min_players, max_players, min_duration_sec, max_duration_sec,
+            requires_tracked_player, enqueue, priority
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        RETURNING rule_id",
+        rule.name,
+        rule.enabled,
+        rule.rule_order,
+        rule.game_map,
+        rule.game_mode,
+        rule.teams,
+        rule.min_players,
+        rule.max_players,
+        rule.min_duration_sec,
+        rule.max_duration_sec,
+        rule.requires_tracked_player,
+        rule.enqueue,
+        rule.priority,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(rule_id)
+}
+
+/// Returns false if there is no such rule
+pub async fn update_rule(
+    db: &PgPool,
+    rule_id: i32,
+    rule: &NewAnalysisRule,
+) -> anyhow::Result<bool> {
+    let res = sqlx::query!(
+        "UPDATE analysis_rules SET
+            name = $2, enabled = $3, rule_order = $4, game_map = $5, game_mode = $6, teams = $7,
+            min_players = $8, max_players = $9, min_duration_sec = $10, max_duration_sec = $11,
+            requires_tracked_player = $12, enqueue = $13, priority = $14
+        WHERE rule_id = $1",
+        rule_id,
+        rule.name,
+        rule.enabled,
+        rule.rule_order,
+        rule.game_map,
+        rule.game_mode,
+        rule.teams,
+        rule.min_players,
+        rule.max_players,
+        rule.min_duration_sec,
+        rule.max_duration_sec,
+        rule.requires_tracked_player,
+        rule.enqueue,
+        rule.priority,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+/// Returns false if there is no such rule
+pub async fn delete_rule(db: &PgPool, rule_id: i32) -> anyhow::Result<bool> {
+    let res = sqlx::query!("DELETE FROM analysis_rules WHERE rule_id = $1", rule_id)
+        .execute(db)
+        .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+/// Facts for one finished game, or for the `limit` most recently finished ones
+pub async fn game_facts(
+    db: &PgPool,
+    game_id: Option<&str>,
+    limit: i64,
+) -> anyhow::Result<Vec<GameFacts>> {
+    let facts = sqlx::query_as!(
+        GameFacts,
+        r#"
+        SELECT
+            fg.game_id AS "game_id!",
+            lo.game_map,
+            fg.result_json->'info'->'config'->>'gameMode' AS game_mode,
+            lo.teams,
+            jsonb_array_length(fg.result_json->'info'->'players') AS num_players,
+            (fg.result_json->'info'->>'duration')::BIGINT AS duration_sec,
+            EXISTS (
+                SELECT 1 FROM social.tracked_player_in_game t WHERE t.game_id = fg.game_id
+            ) AS "has_tracked_player!"
+        FROM finished_games fg
+            JOIN lobbies lo ON lo.game_id = fg.game_id
+        WHERE
+            fg.is_ok
+            AND ($1::TEXT IS NULL OR fg.game_id = $1)
+        ORDER BY fg.inserted_at_unix_sec DESC
+        LIMIT $2
+        "#,
+        game_id,
+        limit
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(facts)
+}
+
+/// Runs the rules on a game that just finished, and queues it if they say so
+pub async fn auto_analyze(db: &PgPool, game_id: &str) -> anyhow::Result<Decision> {
+    let rules = load_rules(db).await?;
+    let Some(game) = game_facts(db, Some(game_id), 1).await?.pop() else {
+        anyhow::bail!("Game {} is not a finished game", game_id);
+    };
+
+    let decision = decide(rules.iter().map(|r| (Some(r.rule_id), &r.rule)), &game);
+    if !decision.enqueue {
+        return Ok(decision);
+    }
+
+    let res = sqlx::query!(
+        "INSERT INTO analysis_queue (game_id, requesting_user_id, priority)
+        SELECT $1, NULL, $2
+        WHERE NOT EXISTS (SELECT 1 FROM analysis_1.completed_analysis WHERE game_id = $1)
+        ON CONFLICT (game_id) WHERE status IN ('Pending', 'Running') DO NOTHING",
+        game_id,
+        decision.priority,
+    )
+    .execute(db)
+    .await?;
+
+    if res.rows_affected() > 0 {
+        tracing::warn!(
+            game_id,
+            rule = decision.rule_name,
+            priority = decision.priority,
+            "Game added to analysis queue."
+        );
+    } else {
+        tracing::info!(game_id, "Game already in analysis queue.");
+    }
+
+    Ok(decision)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(name: &str, json: serde_json::Value) -> NewAnalysisRule {
+        let mut json = json;
+        json["name"] = name.into();
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn game(num_players: Option<i32>) -> GameFacts {
+        GameFacts {
+            game_id: "AbCd1234".into(),
+            game_map: "World".into(),
+            game_mode: Some("Team".into()),
+            teams: -2,
+            num_players,
+            duration_sec: Some(1200),
+            has_tracked_player: false,
+        }
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let g = game(Some(40));
+        assert!(rule("any", serde_json::json!({})).matches(&g));
+        assert!(rule("map", serde_json::json!({"game_map": "world"})).matches(&g));
+        assert!(!rule("map", serde_json::json!({"game_map": "Europe"})).matches(&g));
+        assert!(
+            rule(
+                "duos",
+                serde_json::json!({"teams": -2, "game_mode": "Team"})
+            )
+            .matches(&g)
+        );
+        assert!(!rule("ffa", serde_json::json!({"teams": 0})).matches(&g));
+        assert!(rule("big", serde_json::json!({"min_players": 40})).matches(&g));
+        assert!(!rule("small", serde_json::json!({"max_players": 39})).matches(&g));
+        assert!(!rule("short", serde_json::json!({"max_duration_sec": 600})).matches(&g));
+        assert!(
+            !rule(
+                "tracked",
+                serde_json::json!({"requires_tracked_player": true})
+            )
+            .matches(&g)
+        );
+
+        // Player limits never match a game whose player count we don't know
+        let unknown = game(None);
+        assert!(!rule("big", serde_json::json!({"min_players": 40})).matches(&unknown));
+        assert!(rule("map", serde_json::json!({"game_map": "World"})).matches(&unknown));
+    }
+
+    #[test]
+    fn test_decide_uses_first_match() {
+        let rules = [
+            rule("everything", serde_json::json!({"rule_order": 100})),
+            rule(
+                "no small games",
+                serde_json::json!({"rule_order": 10, "max_players": 10, "enqueue": false}),
+            ),
+            rule(
+                "big duos first",
+                serde_json::json!({"rule_order": 0, "teams": -2, "min_players": 30, "priority": 5}),
+            ),
+            rule(
+                "disabled",
+                serde_json::json!({"rule_order": -1, "enabled": false, "priority": 100}),
+            ),
+        ];
+        let rules: Vec<_> = rules
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (Some(i as i32), r))
+            .collect();
+
+        let big = decide(rules.clone(), &game(Some(40)));
+        assert_eq!(big.rule_name.as_deref(), Some("big duos first"));
+        assert!(big.enqueue);
+        assert_eq!(big.priority, 5);
+
+        let small = decide(rules.clone(), &game(Some(4)));
+        assert_eq!(small.rule_name.as_deref(), Some("no small games"));
+        assert!(!small.enqueue);
+
+        let medium = decide(rules.clone(), &game(Some(20)));
+        assert_eq!(medium.rule_id, Some(0));
+        assert_eq!(medium.priority, 0);
+
+        let nothing = decide(rules[1..].to_vec(), &game(Some(20)));
+        assert_eq!(nothing.rule_id, None);
+        assert!(!nothing.enqueue);
+    }
+}
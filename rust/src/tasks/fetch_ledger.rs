@@ -0,0 +1,138 @@
+//! Remembers games we failed to download, so each one is retried on an increasing schedule and
+//! eventually given up on instead of being fetched on every run forever.
+
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::{Config, api::openfrontapi::OpenFrontError, database::now_unix_sec};
+
+/// Why a game could not be saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "game_fetch_outcome")]
+pub enum GameFetchOutcome {
+    NotFound,
+    FetchError,
+}
+
+/// Wait `base`, `2 * base`, `4 * base`, ... after each failed attempt, up to a day
+pub fn next_fetch_delay(base: Duration, attempts: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(Duration::from_secs(60 * 60 * 24))
+}
+
+/// Some upstream errors mean we can't fetch any game right now, so the rest of the batch
+/// should wait for the next run instead of each burning an attempt.
+pub fn should_stop_batch(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<OpenFrontError>(),
+        Some(OpenFrontError::ChallengePage | OpenFrontError::RateLimited { .. })
+    )
+}
+
+/// Records a failed attempt. Returns true if the game has now been abandoned.
+pub async fn record_failure(
+    database: &PgPool,
+    cfg: &Config,
+    game_id: &str,
+    outcome: GameFetchOutcome,
+    error: Option<String>,
+) -> anyhow::Result<bool> {
+    let previous_attempts = sqlx::query_scalar!(
+        "SELECT attempts FROM game_fetch_ledger WHERE game_id = $1",
+        game_id
+    )
+    .fetch_optional(database)
+    .await?
+    .unwrap_or(0);
+
+    let attempts = previous_attempts + 1;
+    let abandoned = attempts >= cfg.game_fetch_max_attempts as i32;
+    let delay = next_fetch_delay(
+        Duration::from_secs(cfg.game_fetch_retry_base_secs),
+        attempts as u32,
+    );
+    let now = now_unix_sec();
+
+    sqlx::query!(
+        "INSERT INTO game_fetch_ledger
+            (game_id, attempts, last_outcome, last_error, last_attempt_unix_sec, next_check_unix_sec, abandoned)
+        VALUES
+            ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (game_id)
+        DO UPDATE
+            SET attempts = $2
+            , last_outcome = $3
+            , last_error = $4
+            , last_attempt_unix_sec = $5
+            , next_check_unix_sec = $6
+            , abandoned = $7
+        ",
+        game_id,
+        attempts,
+        outcome as GameFetchOutcome,
+        error,
+        now,
+        now + delay.as_secs() as i64,
+        abandoned,
+    )
+    .execute(database)
+    .await?;
+
+    if abandoned {
+        tracing::warn!(
+            game_id,
+            attempts,
+            ?outcome,
+            "Giving up on downloading game {}",
+            game_id
+        );
+    } else {
+        tracing::info!(
+            game_id,
+            attempts,
+            ?outcome,
+            "Failed to download game {}, next check in {}s",
+            game_id,
+            delay.as_secs()
+        );
+    }
+
+    Ok(abandoned)
+}
+
+/// The game was saved, so there is nothing left to retry
+pub async fn clear(database: &PgPool, game_id: &str) -> anyhow::Result<()> {
+    sqlx::query!("DELETE FROM game_fetch_ledger WHERE game_id = $1", game_id)
+        .execute(database)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next_fetch_delay() {
+        let base = Duration::from_secs(15 * 60);
+        assert_eq!(next_fetch_delay(base, 1), base);
+        assert_eq!(next_fetch_delay(base, 2), base * 2);
+        assert_eq!(next_fetch_delay(base, 4), base * 8);
+        assert_eq!(
+            next_fetch_delay(base, 30),
+            Duration::from_secs(60 * 60 * 24)
+        );
+    }
+
+    #[test]
+    fn test_should_stop_batch() {
+        assert!(should_stop_batch(&OpenFrontError::ChallengePage.into()));
+        assert!(should_stop_batch(
+            &OpenFrontError::RateLimited { retry_after: None }.into()
+        ));
+        assert!(!should_stop_batch(&OpenFrontError::Timeout.into()));
+        assert!(!should_stop_batch(&anyhow::anyhow!("unknown state")));
+    }
+}
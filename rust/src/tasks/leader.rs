@@ -0,0 +1,165 @@
+//! Makes sure only one replica runs a task at a time.
+//!
+//! Each singleton task has a Postgres advisory lock. The replica that holds it is the leader and
+//! runs the task, the others stand by and keep trying to take the lock. The lock belongs to a
+//! connection we keep out of the pool, so if the leader dies its connection closes, Postgres
+//! drops the lock, and a standby takes over on its next try.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use sqlx::{Connection, PgConnection, PgPool};
+use tokio::sync::Mutex;
+
+use crate::ActiveTasks;
+
+#[derive(Clone)]
+pub struct LeaderLock {
+    name: String,
+    db: PgPool,
+    /// How often standbys try to take the lock, and how often the leader checks it still has it
+    pub retry: Duration,
+    /// The connection holding the lock, while we are the leader
+    conn: Arc<Mutex<Option<PgConnection>>>,
+}
+
+impl std::fmt::Debug for LeaderLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LeaderLock")
+            .field("name", &self.name)
+            .field("retry", &self.retry)
+            .finish()
+    }
+}
+
+impl LeaderLock {
+    pub fn new(db: PgPool, task: ActiveTasks, retry: Duration) -> Self {
+        LeaderLock {
+            name: lock_name(&task),
+            db,
+            retry,
+            conn: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns true if we are the leader, taking the lock if nobody has it
+    pub async fn acquire(&self) -> anyhow::Result<bool> {
+        let mut conn = self.conn.lock().await;
+        if let Some(c) = conn.as_mut() {
+            if c.ping().await.is_ok() {
+                return Ok(true);
+            }
+            // The connection broke, so the lock is gone with it. Someone else may have it now.
+            tracing::warn!(
+                lock = self.name,
+                "Lost the connection holding a leader lock"
+            );
+            *conn = None;
+        }
+
+        let mut c = self.db.acquire().await?.detach();
+        let locked = sqlx::query_scalar!(
+            r#"SELECT pg_try_advisory_lock(hashtextextended($1, 0)) AS "locked!""#,
+            self.name
+        )
+        .fetch_one(&mut c)
+        .await?;
+
+        if locked {
+            tracing::info!(lock = self.name, "Became the leader");
+            *conn = Some(c);
+        } else {
+            // Closing the connection here is fine, we never took the lock on it
+            c.close().await.ok();
+        }
+
+        Ok(locked)
+    }
+
+    /// Resolves once we are no longer the leader, checking every `retry`
+    pub async fn lost(&self) -> anyhow::Error {
+        loop {
+            tokio::time::sleep(self.retry).await;
+
+            let mut conn = self.conn.lock().await;
+            let Some(c) = conn.as_mut() else {
+                return anyhow::anyhow!("Not the leader for {}", self.name);
+            };
+            if let Err(e) = c.ping().await {
+                *conn = None;
+                return anyhow::anyhow!("Lost the leader lock for {}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// Gives up the lock so another replica can take over right away
+    pub async fn release(&self) {
+        if let Some(mut c) = self.conn.lock().await.take() {
+            let res = sqlx::query_scalar!(
+                "SELECT pg_advisory_unlock(hashtextextended($1, 0))",
+                self.name
+            )
+            .fetch_one(&mut c)
+            .await;
+            if let Err(e) = res {
+                tracing::warn!(lock = self.name, "Failed to release leader lock: {}", e);
+            }
+            c.close().await.ok();
+            tracing::info!(lock = self.name, "Released leader lock");
+        }
+    }
+}
+
+/// Every replica must agree on this for the same task
+fn lock_name(task: &ActiveTasks) -> String {
+    format!("openfrontpro:{:?}", task)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lock_names_differ_per_task() {
+        assert_eq!(
+            lock_name(&ActiveTasks::LookForOpenfrontLobbies),
+            "openfrontpro:LookForOpenfrontLobbies"
+        );
+        assert_ne!(
+            lock_name(&ActiveTasks::LookForFinishedLobbies),
+            lock_name(&ActiveTasks::LookForTrackedPlayerGames)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_one_leader() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let task = ActiveTasks::LookForNewGamesInAnalysisQueue;
+        let first = LeaderLock::new(db.pool.clone(), task.clone(), Duration::from_millis(10));
+        let second = LeaderLock::new(db.pool.clone(), task, Duration::from_millis(10));
+
+        assert!(first.acquire().await.unwrap());
+        assert!(!second.acquire().await.unwrap());
+        // Asking again keeps the lock
+        assert!(first.acquire().await.unwrap());
+        assert!(!second.acquire().await.unwrap());
+
+        // Other tasks have their own lock
+        let other = LeaderLock::new(
+            db.pool.clone(),
+            ActiveTasks::LookForFinishedLobbies,
+            Duration::from_millis(10),
+        );
+        assert!(other.acquire().await.unwrap());
+
+        first.release().await;
+        assert!(second.acquire().await.unwrap());
+        assert!(!first.acquire().await.unwrap());
+        assert!(first.lost().await.to_string().contains("Not the leader"));
+
+        second.release().await;
+        other.release().await;
+    }
+}
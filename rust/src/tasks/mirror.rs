@@ -0,0 +1,524 @@
+//! Mirror lobbies and finished games from another openfront.pro instance, so dev and staging
+//! databases can be seeded from production.
+//!
+//! Everything is upserted, so the mirror can be run as often as you like.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::{Config, analysis, database::GameConfig, game_record::FinishedGame};
+
+/// Entry from `GET /api/v1/lobbies` on the upstream
+///
+/// `teams` is skipped here because [`crate::database::PlayerTeams`] can only be read back from
+/// the database, so we recompute it from the lobby config instead.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UpstreamLobbySummary {
+    pub game_id: String,
+    pub approx_num_players: i32,
+    pub last_seen_unix_sec: i64,
+    pub completed: bool,
+    pub analysis_complete: bool,
+}
+
+/// Entry from `GET /api/v1/lobbies/{id}` on the upstream
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UpstreamLobby {
+    pub game_id: String,
+    pub max_players: i32,
+    pub game_map: String,
+    pub approx_num_players: i32,
+    pub first_seen_unix_sec: i64,
+    pub last_seen_unix_sec: i64,
+    pub completed: bool,
+    pub lobby_config_json: GameConfig,
+}
+
+/// Talks to the public API of an openfront.pro instance
+pub struct MirrorClient {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl MirrorClient {
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(format!("openfrontpro-mirror/{}", env!("CARGO_PKG_VERSION")))
+            .timeout(std::time::Duration::from_secs(cfg.openfront_timeout_secs))
+            .build()?;
+
+        Ok(MirrorClient {
+            client,
+            base_url: cfg.mirror_upstream_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Lobbies first seen after `after_unix_sec`, and last seen before `before_unix_sec`. The
+    /// upstream sends at most 100, most recently seen first.
+    pub async fn get_lobbies(
+        &self,
+        after_unix_sec: i64,
+        before_unix_sec: Option<i64>,
+    ) -> anyhow::Result<Vec<UpstreamLobbySummary>> {
+        let mut url = format!("{}/api/v1/lobbies?after={}", self.base_url, after_unix_sec);
+        if let Some(before) = before_unix_sec {
+            url.push_str(&format!("&before={}", before));
+        }
+        let lobbies = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Invalid lobby list from {}", url))?;
+
+        Ok(lobbies)
+    }
+
+    pub async fn get_lobby(&self, game_id: &str) -> anyhow::Result<UpstreamLobby> {
+        let url = format!("{}/api/v1/lobbies/{}", self.base_url, game_id);
+        let lobby = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Invalid lobby from {}", url))?;
+
+        Ok(lobby)
+    }
+
+    /// `None` if the upstream doesn't have a game record for this game
+    pub async fn get_game(&self, game_id: &str) -> anyhow::Result<Option<FinishedGame>> {
+        let url = format!("{}/api/v1/games/{}", self.base_url, game_id);
+        let res = self.client.get(&url).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let raw = res.error_for_status()?.json().await?;
+        let game = FinishedGame::from_value(raw)
+            .with_context(|| format!("Invalid game record from {}", url))?;
+
+        Ok(Some(game))
+    }
+
+    /// The analysis of a game as NDJSON, see [`analysis::ingest`]. `None` if the upstream doesn't
+    /// have it, or is too old to export analyses.
+    pub async fn get_analysis(&self, game_id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let url = format!("{}/api/v1/analysis/{}/export", self.base_url, game_id);
+        let res = self.client.get(&url).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body = res.error_for_status()?.bytes().await?;
+        Ok(Some(body.to_vec()))
+    }
+}
+
+/// What one run of the mirror did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorReport {
+    pub lobbies_seen: usize,
+    pub lobbies_inserted: usize,
+    pub games_inserted: usize,
+    pub analyses_inserted: usize,
+    pub queued_for_analysis: usize,
+    pub failed: usize,
+}
+
+pub async fn pull_from_upstream(database: PgPool, cfg: Arc<Config>) -> anyhow::Result<()> {
+    let mirror = MirrorClient::new(&cfg)?;
+    let after = crate::database::now_unix_sec() - cfg.mirror_lookback_secs;
+
+    let report = mirror_lobbies(&mirror, &database, after, cfg.mirror_analysis).await?;
+    tracing::info!(
+        report.lobbies_seen,
+        report.lobbies_inserted,
+        report.games_inserted,
+        report.analyses_inserted,
+        report.queued_for_analysis,
+        report.failed,
+        "Finished mirroring from {}",
+        cfg.mirror_upstream_url
+    );
+
+    Ok(())
+}
+
+/// Mirrors every upstream lobby first seen after `after_unix_sec`. A lobby that fails is logged
+/// and counted, and does not stop the rest.
+pub async fn mirror_lobbies(
+    mirror: &MirrorClient,
+    database: &PgPool,
+    after_unix_sec: i64,
+    pull_analysis: bool,
+) -> anyhow::Result<MirrorReport> {
+    let mut report = MirrorReport::default();
+    let mut seen = HashSet::new();
+    let mut before = None;
+
+    loop {
+        let page = mirror
+            .get_lobbies(after_unix_sec, before)
+            .await
+            .context("Failed to fetch lobbies from upstream")?;
+        let Some(oldest) = page.iter().map(|l| l.last_seen_unix_sec).min() else {
+            break;
+        };
+
+        // `before` is exclusive, so the next page asks for the oldest second again in case more
+        // lobbies were last seen in it. Those we already have are skipped.
+        let lobbies: Vec<_> = page
+            .into_iter()
+            .filter(|l| seen.insert(l.game_id.clone()))
+            .collect();
+        if lobbies.is_empty() {
+            break;
+        }
+        report.lobbies_seen += lobbies.len();
+
+        for lobby in lobbies {
+            if let Err(e) = mirror_lobby(mirror, database, &lobby, pull_analysis, &mut report).await
+            {
+                tracing::error!("Failed to mirror lobby {}: {:?}", lobby.game_id, e);
+                report.failed += 1;
+            }
+        }
+
+        before = Some(oldest + 1);
+    }
+
+    Ok(report)
+}
+
+async fn mirror_lobby(
+    mirror: &MirrorClient,
+    database: &PgPool,
+    summary: &UpstreamLobbySummary,
+    pull_analysis: bool,
+    report: &mut MirrorReport,
+) -> anyhow::Result<()> {
+    let game_id = summary.game_id.as_str();
+    let local = sqlx::query!(
+        r#"SELECT
+            (fg.game_id IS NOT NULL) AS "has_game!",
+            (co.game_id IS NOT NULL) AS "has_analysis!"
+        FROM
+            lobbies lo
+            LEFT JOIN finished_games fg ON lo.game_id = fg.game_id
+            LEFT JOIN analysis_1.completed_analysis co ON lo.game_id = co.game_id
+        WHERE lo.game_id = $1"#,
+        game_id
+    )
+    .fetch_optional(database)
+    .await?;
+
+    if local.is_none() {
+        // We need the full lobby for the config
+        let lobby = mirror.get_lobby(game_id).await?;
+        upsert_lobby(database, &lobby).await?;
+        report.lobbies_inserted += 1;
+    } else {
+        sqlx::query!(
+            "UPDATE lobbies
+            SET
+                approx_num_players = $2,
+                last_seen_unix_sec = GREATEST(last_seen_unix_sec, $3),
+                completed = completed OR $4
+            WHERE game_id = $1",
+            game_id,
+            summary.approx_num_players,
+            summary.last_seen_unix_sec,
+            summary.completed,
+        )
+        .execute(database)
+        .await?;
+    }
+
+    let has_game = local.as_ref().is_some_and(|l| l.has_game);
+    if summary.completed && !has_game {
+        if let Some(game) = mirror.get_game(game_id).await? {
+            if insert_mirrored_game(database, game_id, &game).await? {
+                report.games_inserted += 1;
+            }
+        }
+    }
+
+    let has_analysis = local.as_ref().is_some_and(|l| l.has_analysis);
+    if pull_analysis && summary.analysis_complete && !has_analysis {
+        if let Some(body) = mirror.get_analysis(game_id).await? {
+            let payload = analysis::ingest::parse_payload(&body)?;
+            analysis::ingest::ingest_analysis(database, game_id, &payload, None).await?;
+            report.analyses_inserted += 1;
+            return Ok(());
+        }
+
+        // Analysis is deterministic from the game record, so if the upstream can't send it we
+        // run it again here.
+        let res = sqlx::query!(
+            "INSERT INTO analysis_queue (game_id, requesting_user_id)
+            SELECT $1, NULL
+            WHERE EXISTS (SELECT 1 FROM finished_games WHERE game_id = $1 AND is_ok)
+            ON CONFLICT (game_id) WHERE status IN ('Pending', 'Running') DO NOTHING",
+            game_id
+        )
+        .execute(database)
+        .await?;
+        report.queued_for_analysis += res.rows_affected() as usize;
+    }
+
+    Ok(())
+}
+
+async fn upsert_lobby(database: &PgPool, lobby: &UpstreamLobby) -> anyhow::Result<()> {
+    let teams: i32 = lobby.lobby_config_json.teams().into();
+
+    sqlx::query!(
+        "INSERT INTO
+            lobbies (game_id, teams, max_players, game_map, approx_num_players, first_seen_unix_sec, last_seen_unix_sec, completed, lobby_config_json)
+        VALUES
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT (game_id)
+        DO UPDATE
+            SET approx_num_players = EXCLUDED.approx_num_players
+            , first_seen_unix_sec = LEAST(lobbies.first_seen_unix_sec, EXCLUDED.first_seen_unix_sec)
+            , last_seen_unix_sec = GREATEST(lobbies.last_seen_unix_sec, EXCLUDED.last_seen_unix_sec)
+            , completed = lobbies.completed OR EXCLUDED.completed
+        ",
+        lobby.game_id,
+        teams,
+        lobby.max_players,
+        lobby.game_map,
+        lobby.approx_num_players,
+        lobby.first_seen_unix_sec,
+        lobby.last_seen_unix_sec,
+        lobby.completed,
+        serde_json::to_value(&lobby.lobby_config_json)?,
+    )
+    .execute(database)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns false if we already had this game
+async fn insert_mirrored_game(
+    database: &PgPool,
+    game_id: &str,
+    game: &FinishedGame,
+) -> anyhow::Result<bool> {
+    let res = sqlx::query!(
+        "INSERT INTO finished_games (game_id, result_json, is_ok)
+        VALUES ($1, $2, true)
+        ON CONFLICT (game_id) DO NOTHING",
+        game_id,
+        game.raw,
+    )
+    .execute(database)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let intents = analysis::intents::analyze_intents(&game.record);
+    analysis::intents::save_intent_analysis(database, game_id, &intents).await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{
+        Json, Router,
+        extract::{Path, Query},
+        http::StatusCode,
+        routing::get,
+    };
+    use clap::Parser;
+    use serde_json::json;
+
+    /// Lobbies that aren't finished, on top of the one finished game. More than fit on a page.
+    const OPEN_LOBBIES: i64 = 150;
+
+    #[derive(serde::Deserialize)]
+    struct LobbiesParams {
+        after: i64,
+        before: Option<i64>,
+    }
+
+    fn upstream_lobby(
+        game_id: &str,
+        completed: bool,
+        last_seen_unix_sec: i64,
+    ) -> serde_json::Value {
+        json!({
+            "game_id": game_id,
+            "teams": {"group": "FFA"},
+            "max_players": 50,
+            "game_map": "World",
+            "approx_num_players": 50,
+            "first_seen_unix_sec": 1_750_000_000,
+            "last_seen_unix_sec": last_seen_unix_sec,
+            "completed": completed,
+            "analysis_complete": completed,
+        })
+    }
+
+    /// Serves a tiny openfront.pro API with one finished and analyzed game, and a lot of lobbies
+    /// without a record. Every 10 lobbies were last seen in the same second.
+    async fn stand_in_upstream() -> String {
+        let mut lobbies = vec![upstream_lobby("mygame01", true, 1_750_001_000)];
+        lobbies.extend(
+            (0..OPEN_LOBBIES)
+                .map(|i| upstream_lobby(&format!("lobby{:03}", i), false, 1_750_000_100 + i / 10)),
+        );
+
+        let app = Router::new()
+            .route(
+                "/api/v1/lobbies",
+                // Like the real lobbies_handler: newest first, 100 at most
+                get(move |Query(params): Query<LobbiesParams>| async move {
+                    let mut page: Vec<_> = lobbies
+                        .iter()
+                        .filter(|l| l["first_seen_unix_sec"].as_i64().unwrap() > params.after)
+                        .filter(|l| {
+                            params
+                                .before
+                                .is_none_or(|b| l["last_seen_unix_sec"].as_i64().unwrap() < b)
+                        })
+                        .cloned()
+                        .collect();
+                    page.sort_by_key(|l| std::cmp::Reverse(l["last_seen_unix_sec"].as_i64()));
+                    page.truncate(100);
+                    Json(page)
+                }),
+            )
+            .route(
+                "/api/v1/lobbies/{id}",
+                get(move |Path(id): Path<String>| async move {
+                    let mut lobby = upstream_lobby(&id, id == "mygame01", 1_750_000_100);
+                    lobby["lobby_config_json"] = json!({
+                        "gameMap": "World",
+                        "gameType": "Public",
+                        "difficulty": "Medium",
+                        "disableNPCs": false,
+                        "infiniteGold": false,
+                        "infiniteTroops": false,
+                        "instantBuild": false,
+                        "gameMode": "Free For All",
+                        "bots": 400,
+                        "disabledUnits": [],
+                        "maxPlayers": 50,
+                        "playerTeams": "Duos",
+                    });
+                    Json(lobby)
+                }),
+            )
+            .route(
+                "/api/v1/games/{id}",
+                get(|Path(id): Path<String>| async move {
+                    if id != "mygame01" {
+                        return Err(StatusCode::NOT_FOUND);
+                    }
+                    Ok(Json(crate::utils::load_game_in_test("mygame").unwrap()))
+                }),
+            )
+            .route(
+                "/api/v1/analysis/{id}/export",
+                get(|Path(id): Path<String>| async move {
+                    if id != "mygame01" {
+                        return Err(StatusCode::NOT_FOUND);
+                    }
+                    Ok(concat!(
+                        r#"{"table":"header","analysis_engine_version":"v1"}"#,
+                        "\n",
+                        r#"{"table":"general_event","tick":5,"event_type":"Win","data":{}}"#,
+                        "\n",
+                    ))
+                }),
+            );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn test_mirror_client_against_stand_in() {
+        let base_url = stand_in_upstream().await;
+        let cfg = Config::parse_from(["openfrontpro", "--mirror-upstream-url", &base_url]);
+        let mirror = MirrorClient::new(&cfg).unwrap();
+
+        let lobbies = mirror.get_lobbies(0, None).await.unwrap();
+        assert_eq!(lobbies.len(), 100);
+        assert!(lobbies[0].completed && lobbies[0].analysis_complete);
+        let older = mirror.get_lobbies(0, Some(1_750_000_105)).await.unwrap();
+        assert_eq!(older.len(), 50);
+
+        let lobby = mirror.get_lobby("mygame01").await.unwrap();
+        assert_eq!(lobby.max_players, 50);
+        assert_eq!(i32::from(lobby.lobby_config_json.teams()), -2);
+
+        let game = mirror.get_game("mygame01").await.unwrap().unwrap();
+        assert!(!game.record.turns.is_empty());
+        assert!(mirror.get_game("nogame01").await.unwrap().is_none());
+
+        assert!(mirror.get_analysis("mygame01").await.unwrap().is_some());
+        assert!(mirror.get_analysis("nogame01").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_lobbies_is_idempotent() {
+        let Some(db) = crate::utils::test_database().await else {
+            return;
+        };
+        let base_url = stand_in_upstream().await;
+        let cfg = Config::parse_from(["openfrontpro", "--mirror-upstream-url", &base_url]);
+        let mirror = MirrorClient::new(&cfg).unwrap();
+
+        let first = mirror_lobbies(&mirror, &db.pool, 0, true).await.unwrap();
+        assert_eq!(first.failed, 0);
+        assert_eq!(first.lobbies_seen, OPEN_LOBBIES as usize + 1);
+        assert_eq!(first.lobbies_inserted, OPEN_LOBBIES as usize + 1);
+        assert_eq!(first.games_inserted, 1);
+        assert_eq!(first.analyses_inserted, 1);
+        assert_eq!(first.queued_for_analysis, 0);
+
+        let second = mirror_lobbies(&mirror, &db.pool, 0, true).await.unwrap();
+        assert_eq!(second.failed, 0);
+        assert_eq!(second.lobbies_seen, OPEN_LOBBIES as usize + 1);
+        assert_eq!(second.lobbies_inserted, 0);
+        assert_eq!(second.games_inserted, 0);
+        assert_eq!(second.analyses_inserted, 0);
+
+        let counts = sqlx::query!(
+            r#"SELECT
+                (SELECT COUNT(*) FROM lobbies) AS "lobbies!",
+                (SELECT COUNT(*) FROM finished_games) AS "games!",
+                (SELECT COUNT(*) FROM analysis_1.completed_analysis) AS "analyses!",
+                (SELECT COUNT(*) FROM analysis_1.general_events) AS "events!",
+                (SELECT COUNT(*) FROM analysis_queue) AS "queued!""#
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(counts.lobbies, OPEN_LOBBIES + 1);
+        assert_eq!(counts.games, 1);
+        assert_eq!(counts.analyses, 1);
+        assert_eq!(counts.events, 1);
+        assert_eq!(counts.queued, 0);
+    }
+}
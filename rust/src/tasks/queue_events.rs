@@ -0,0 +1,155 @@
+//! Changes to the analysis queue, pushed by Postgres with NOTIFY instead of polled for.
+//!
+//! A trigger on `analysis_queue` notifies the `analysis_queue` channel whenever a game is queued
+//! or its status changes. [`QueueEvents::listen`] forwards those to everyone who subscribed in
+//! this process. Missed events are possible (the listener reconnecting, a slow subscriber), so
+//! anything that reacts to them should still poll now and then.
+
+use std::time::Duration;
+
+use sqlx::{PgPool, postgres::PgListener};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use super::registry::TaskHandle;
+use crate::AnalysisQueueStatus;
+
+pub const CHANNEL: &str = "analysis_queue";
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct AnalysisQueueEvent {
+    pub game_id: String,
+    pub status: AnalysisQueueStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueEvents {
+    tx: broadcast::Sender<AnalysisQueueEvent>,
+}
+
+impl Default for QueueEvents {
+    fn default() -> Self {
+        QueueEvents {
+            tx: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl QueueEvents {
+    pub fn subscribe(&self) -> broadcast::Receiver<AnalysisQueueEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Forwards notifications until shutdown, reconnecting whenever the connection drops
+    pub async fn listen(self, db: PgPool, shutdown: CancellationToken) {
+        while !shutdown.is_cancelled() {
+            if let Err(e) = self.listen_once(&db, &shutdown).await {
+                tracing::warn!(
+                    "Analysis queue listener failed, falling back to polling: {}",
+                    e
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    _ = shutdown.cancelled() => {}
+                }
+            }
+        }
+    }
+
+    async fn listen_once(&self, db: &PgPool, shutdown: &CancellationToken) -> anyhow::Result<()> {
+        let mut listener = PgListener::connect_with(db).await?;
+        listener.listen(CHANNEL).await?;
+        tracing::info!("Listening for analysis queue changes");
+
+        loop {
+            let notification = tokio::select! {
+                n = listener.recv() => n?,
+                _ = shutdown.cancelled() => return Ok(()),
+            };
+
+            match serde_json::from_str::<AnalysisQueueEvent>(notification.payload()) {
+                // Nobody subscribed is fine
+                Ok(event) => drop(self.tx.send(event)),
+                Err(e) => tracing::warn!(
+                    payload = notification.payload(),
+                    "Bad analysis queue notification: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Starts a run of the task whenever a game is queued. The task's own schedule is then only
+    /// a fallback for missed events.
+    pub fn wake_on_pending(&self, handle: TaskHandle, shutdown: CancellationToken) {
+        let mut rx = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    e = rx.recv() => e,
+                    _ = shutdown.cancelled() => return,
+                };
+
+                match event {
+                    Ok(AnalysisQueueEvent {
+                        status: AnalysisQueueStatus::Pending,
+                        ..
+                    }) => handle.trigger(),
+                    Ok(_) => {}
+                    // We missed some, one of them may have been a new game
+                    Err(broadcast::error::RecvError::Lagged(_)) => handle.trigger(),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ActiveTasks;
+    use crate::tasks::registry::TaskRegistry;
+
+    #[test]
+    fn test_parse_notification() {
+        let event: AnalysisQueueEvent =
+            serde_json::from_str(r#"{"game_id" : "AbCd1234", "status" : "Pending"}"#).unwrap();
+        assert_eq!(event.game_id, "AbCd1234");
+        assert_eq!(event.status, AnalysisQueueStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_wake_on_pending() {
+        let registry = TaskRegistry::default();
+        let handle = registry.register(ActiveTasks::LookForNewGamesInAnalysisQueue);
+        let events = QueueEvents::default();
+        events.wake_on_pending(handle.clone(), registry.shutdown_token());
+
+        let send = |status| {
+            events
+                .tx
+                .send(AnalysisQueueEvent {
+                    game_id: "AbCd1234".into(),
+                    status,
+                })
+                .unwrap();
+        };
+
+        send(AnalysisQueueStatus::Completed);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!handle.status().run_requested);
+
+        send(AnalysisQueueStatus::Pending);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(handle.status().run_requested);
+
+        // Paused tasks ignore events
+        let handle = registry.register(ActiveTasks::LookForOldRunningGames);
+        events.wake_on_pending(handle.clone(), registry.shutdown_token());
+        handle.pause();
+        send(AnalysisQueueStatus::Pending);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!handle.status().run_requested);
+    }
+}
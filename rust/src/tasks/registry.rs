@@ -0,0 +1,468 @@
+//! Keeps track of what every background task is doing, for `GET /api/v1/admin/tasks`, lets
+//! admins pause, resume or poke them, and stops them all on shutdown.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use tokio::sync::Notify;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use super::{TaskSettings, schedule::TaskOverride};
+use crate::{ActiveTasks, database::now_unix_sec};
+
+/// What a task has been doing
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct TaskStatus {
+    pub task: ActiveTasks,
+    /// True while the task is inside a run, false while it sleeps between runs
+    pub running: bool,
+    pub run_count: u64,
+    pub error_count: u64,
+    pub last_start_unix_sec: Option<i64>,
+    pub last_finish_unix_sec: Option<i64>,
+    pub last_success_unix_sec: Option<i64>,
+    pub last_error: Option<String>,
+    pub last_error_unix_sec: Option<i64>,
+    /// How many runs in a row have failed
+    pub backoff_stacks: usize,
+    /// When the next run starts, if the task is sleeping
+    pub next_run_unix_sec: Option<i64>,
+    /// Paused tasks finish their current run and then wait to be resumed
+    pub paused: bool,
+    /// An admin asked for a run, and it hasn't started yet
+    pub run_requested: bool,
+    /// Another replica holds this task's leader lock, so this one only waits to take over
+    pub standby: bool,
+}
+
+/// Every task started with [`super::keep_task_alive`]
+#[derive(Debug, Clone, Default)]
+pub struct TaskRegistry {
+    tasks: Arc<Mutex<Vec<TaskHandle>>>,
+    /// From `--task-settings`, applied as each task starts
+    overrides: Arc<Vec<TaskOverride>>,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl TaskRegistry {
+    pub fn with_overrides(overrides: Vec<TaskOverride>) -> Self {
+        TaskRegistry {
+            overrides: Arc::new(overrides),
+            ..Default::default()
+        }
+    }
+
+    pub fn register(&self, task: ActiveTasks) -> TaskHandle {
+        let handle = TaskHandle {
+            overrides: self
+                .overrides
+                .iter()
+                .filter(|o| o.task == task)
+                .cloned()
+                .collect(),
+            shutdown: self.shutdown.clone(),
+            tracker: self.tracker.clone(),
+            wake: Arc::new(Notify::new()),
+            status: Arc::new(Mutex::new(TaskStatus {
+                task,
+                running: false,
+                run_count: 0,
+                error_count: 0,
+                last_start_unix_sec: None,
+                last_finish_unix_sec: None,
+                last_success_unix_sec: None,
+                last_error: None,
+                last_error_unix_sec: None,
+                backoff_stacks: 0,
+                next_run_unix_sec: None,
+                paused: false,
+                run_requested: false,
+                standby: false,
+            })),
+        };
+
+        self.tasks
+            .lock()
+            .expect("Task registry lock poisoned")
+            .push(handle.clone());
+
+        handle
+    }
+
+    /// Status of every task, in the order they were started
+    pub fn snapshot(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .lock()
+            .expect("Task registry lock poisoned")
+            .iter()
+            .map(|t| t.status())
+            .collect()
+    }
+
+    pub fn find(&self, task: &ActiveTasks) -> Option<TaskHandle> {
+        self.tasks
+            .lock()
+            .expect("Task registry lock poisoned")
+            .iter()
+            .find(|t| t.status().task == *task)
+            .cloned()
+    }
+
+    /// Cancelled when the server starts shutting down
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Tells every task to stop and waits for their current runs to finish. Returns false if
+    /// they didn't finish within `timeout`.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.shutdown.cancel();
+        self.tracker.close();
+
+        tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+}
+
+/// Used by a single task to report on itself
+#[derive(Debug, Clone)]
+pub struct TaskHandle {
+    status: Arc<Mutex<TaskStatus>>,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+    /// Wakes the task up early after a pause, resume or run now
+    wake: Arc<Notify>,
+    overrides: Vec<TaskOverride>,
+}
+
+impl TaskHandle {
+    /// Spawns the task so that [`TaskRegistry::shutdown`] waits for it
+    pub fn spawn<F>(self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(task);
+    }
+
+    /// The settings the task was started with, after applying `--task-settings`
+    pub fn configure(&self, mut settings: TaskSettings) -> TaskSettings {
+        for o in &self.overrides {
+            o.apply(&mut settings);
+        }
+        settings
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status
+            .lock()
+            .expect("Task status lock poisoned")
+            .clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TaskStatus)) {
+        f(&mut self.status.lock().expect("Task status lock poisoned"));
+    }
+
+    /// A run asked for while on standby can't happen here, so the request is dropped
+    pub fn set_standby(&self, standby: bool) {
+        self.update(|s| {
+            s.standby = standby;
+            if standby {
+                s.run_requested = false;
+            }
+        });
+    }
+
+    pub fn started(&self) {
+        self.update(|s| {
+            s.running = true;
+            s.run_requested = false;
+            s.run_count += 1;
+            s.last_start_unix_sec = Some(now_unix_sec());
+            s.next_run_unix_sec = None;
+        });
+    }
+
+    pub fn succeeded(&self) {
+        self.update(|s| {
+            let now = now_unix_sec();
+            s.running = false;
+            s.last_finish_unix_sec = Some(now);
+            s.last_success_unix_sec = Some(now);
+            s.backoff_stacks = 0;
+        });
+    }
+
+    pub fn failed(&self, e: &anyhow::Error) {
+        self.update(|s| {
+            let now = now_unix_sec();
+            s.running = false;
+            s.error_count += 1;
+            s.last_finish_unix_sec = Some(now);
+            s.last_error = Some(format!("{:#}", e));
+            s.last_error_unix_sec = Some(now);
+            s.backoff_stacks += 1;
+        });
+    }
+
+    /// Waits `wait`, then for as long as the task is paused. Returns early if an admin asks for
+    /// a run, and returns false if the server is shutting down.
+    pub async fn wait_for_next_run(&self, wait: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // Listen before reading the flags so a change in between isn't missed
+            let notified = self.wake.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.shutdown.is_cancelled() {
+                return false;
+            }
+
+            let status = self.status();
+            if status.run_requested {
+                return true;
+            }
+
+            if status.paused {
+                self.update(|s| s.next_run_unix_sec = None);
+                tokio::select! {
+                    _ = notified => {}
+                    _ = self.shutdown.cancelled() => {}
+                }
+                continue;
+            }
+
+            if tokio::time::Instant::now() >= deadline {
+                return true;
+            }
+
+            let remaining = deadline - tokio::time::Instant::now();
+            self.update(|s| {
+                s.next_run_unix_sec = Some(now_unix_sec() + remaining.as_secs() as i64)
+            });
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {}
+                _ = notified => {}
+                _ = self.shutdown.cancelled() => {}
+            }
+        }
+    }
+
+    pub fn pause(&self) {
+        self.update(|s| s.paused = true);
+        tracing::warn!(task = ?self.status().task, "Task paused");
+        self.wake.notify_waiters();
+    }
+
+    pub fn resume(&self) {
+        self.update(|s| s.paused = false);
+        tracing::warn!(task = ?self.status().task, "Task resumed");
+        self.wake.notify_waiters();
+    }
+
+    /// Start a run early because something the task waits for happened. Unlike
+    /// [`Self::run_now`], this does nothing while the task is paused.
+    pub fn trigger(&self) {
+        let mut triggered = false;
+        self.update(|s| {
+            if !s.paused {
+                s.run_requested = true;
+                triggered = true;
+            }
+        });
+        if triggered {
+            self.wake.notify_waiters();
+        }
+    }
+
+    /// Start a run as soon as the current one (if any) finishes. This works while paused, and
+    /// the task stays paused afterwards.
+    pub fn run_now(&self) {
+        self.update(|s| s.run_requested = true);
+        tracing::warn!(task = ?self.status().task, "Task run requested");
+        self.wake.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::tasks::{BackoffStrategy, keep_task_alive, schedule::Schedule};
+
+    #[tokio::test]
+    async fn test_registry_tracks_runs() {
+        let registry = TaskRegistry::default();
+        let calls = Arc::new(AtomicU32::new(0));
+
+        let c = calls.clone();
+        keep_task_alive(
+            registry.register(ActiveTasks::LookForOldSessions),
+            move || {
+                let c = c.clone();
+                async move {
+                    // Fail twice, then always succeed
+                    if c.fetch_add(1, Ordering::SeqCst) < 2 {
+                        anyhow::bail!("Broken on purpose");
+                    }
+                    Ok(())
+                }
+            },
+            TaskSettings {
+                schedule: Schedule::Every(Duration::from_millis(5)),
+                backoff_strategy: BackoffStrategy::Linear {
+                    start: Duration::ZERO,
+                    increment: Duration::ZERO,
+                    max_stacks: 1,
+                },
+                ..Default::default()
+            },
+        );
+
+        while calls.load(Ordering::SeqCst) < 4 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let tasks = registry.snapshot();
+        assert_eq!(tasks.len(), 1);
+        let status = &tasks[0];
+        assert!(status.run_count >= 4);
+        assert_eq!(status.error_count, 2);
+        assert_eq!(status.backoff_stacks, 0);
+        assert_eq!(status.last_error.as_deref(), Some("Broken on purpose"));
+        assert!(status.last_success_unix_sec.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_current_run() {
+        let registry = TaskRegistry::default();
+        let finished = Arc::new(AtomicU32::new(0));
+
+        let f = finished.clone();
+        keep_task_alive(
+            registry.register(ActiveTasks::LookForOldSessions),
+            move || {
+                let f = f.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    f.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            },
+            TaskSettings {
+                schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+                ..Default::default()
+            },
+        );
+
+        // Shut down in the middle of the first run. The run finishes, and the hour long sleep
+        // after it is skipped.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(registry.shutdown(Duration::from_secs(5)).await);
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert!(!registry.snapshot()[0].running);
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume_run_now() {
+        let registry = TaskRegistry::default();
+        let calls = Arc::new(AtomicU32::new(0));
+
+        let c = calls.clone();
+        let handle = registry.register(ActiveTasks::LookForTrackedPlayerGames);
+        keep_task_alive(
+            handle.clone(),
+            move || {
+                let c = c.clone();
+                async move {
+                    c.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            },
+            TaskSettings {
+                schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+                ..Default::default()
+            },
+        );
+
+        let wait_for_calls = |n: u32| {
+            let calls = calls.clone();
+            async move {
+                while calls.load(Ordering::SeqCst) < n {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+
+        // The first run starts right away, the second only when asked for
+        wait_for_calls(1).await;
+        assert!(registry.snapshot()[0].next_run_unix_sec.is_some());
+        registry
+            .find(&ActiveTasks::LookForTrackedPlayerGames)
+            .unwrap()
+            .run_now();
+        wait_for_calls(2).await;
+
+        // Paused tasks don't run on their own, but can still be run by hand
+        handle.pause();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = handle.status();
+        assert!(status.paused);
+        assert_eq!(status.next_run_unix_sec, None);
+        handle.run_now();
+        wait_for_calls(3).await;
+        assert!(handle.status().paused);
+
+        handle.resume();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = handle.status();
+        assert!(!status.paused);
+        assert!(status.next_run_unix_sec.is_some());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        assert!(registry.find(&ActiveTasks::PullLobbiesFromPROD).is_none());
+        assert!(registry.shutdown(Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test]
+    async fn test_overrides_and_timeout() {
+        let registry = TaskRegistry::with_overrides(vec![
+            "LookForOldSessions:every=0;timeout=0".parse().unwrap(),
+            "LookForFinishedLobbies:timeout=0".parse().unwrap(),
+        ]);
+        let handle = registry.register(ActiveTasks::LookForOldSessions);
+
+        // Only this task's own overrides apply
+        let settings = handle.configure(TaskSettings {
+            schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+            timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        assert!(matches!(settings.schedule, Schedule::Every(d) if d.is_zero()));
+        assert_eq!(settings.timeout, None);
+
+        // A run that never finishes is cut off and counted as a failure
+        keep_task_alive(
+            registry.register(ActiveTasks::LookForTrackedPlayerGames),
+            || std::future::pending(),
+            TaskSettings {
+                schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+                timeout: Some(Duration::from_millis(10)),
+                ..Default::default()
+            },
+        );
+
+        while registry.snapshot()[1].error_count == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let status = &registry.snapshot()[1];
+        assert!(!status.running);
+        assert_eq!(status.last_error.as_deref(), Some("Timed out after 10ms"));
+        assert!(registry.shutdown(Duration::from_secs(5)).await);
+    }
+}
@@ -0,0 +1,236 @@
+//! When tasks run, and how that can be changed per task from the config.
+//!
+//! ```sh
+//! # Run the tracked player sweep at :00 and :30, giving up on a run after 10 minutes
+//! openfrontpro --task-settings "LookForTrackedPlayerGames:cron=0,30 * * * *;timeout=600"
+//! ```
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use rand::Rng;
+
+use super::{BackoffStrategy, TaskSettings};
+use crate::ActiveTasks;
+
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Wait this long after each run finishes. The first run starts right away.
+    Every(Duration),
+    /// Run at the times given by a cron expression, in UTC. The first run waits for the next
+    /// matching time.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Accepts the usual 5 fields (minute hour day month weekday), or 6 and 7 with seconds and
+    /// years
+    pub fn cron(expression: &str) -> anyhow::Result<Schedule> {
+        let expression = expression.trim();
+        let expression = if expression.split_whitespace().count() == 5 {
+            format!("0 {}", expression)
+        } else {
+            expression.to_string()
+        };
+
+        let schedule = cron::Schedule::from_str(&expression)
+            .map_err(|e| anyhow::anyhow!("Invalid cron expression {:?}: {}", expression, e))?;
+        if schedule.upcoming(chrono::Utc).next().is_none() {
+            anyhow::bail!("Cron expression {:?} never runs", expression);
+        }
+
+        Ok(Schedule::Cron(Box::new(schedule)))
+    }
+
+    pub fn first_wait(&self) -> Duration {
+        match self {
+            Schedule::Every(_) => Duration::ZERO,
+            Schedule::Cron(_) => self.next_wait(),
+        }
+    }
+
+    /// How long to wait from now until the next run
+    pub fn next_wait(&self) -> Duration {
+        match self {
+            Schedule::Every(d) => *d,
+            Schedule::Cron(schedule) => match schedule.upcoming(chrono::Utc).next() {
+                Some(next) => (next - chrono::Utc::now()).to_std().unwrap_or_default(),
+                // Only possible for expressions that stop matching in some future year
+                None => Duration::from_secs(60 * 60 * 24 * 365),
+            },
+        }
+    }
+}
+
+/// A random extra wait of up to `jitter`, so replicas and tasks don't all fire at once
+pub fn jitter(jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return Duration::ZERO;
+    }
+
+    rand::rng().random_range(Duration::ZERO..=jitter)
+}
+
+/// Settings for one task from `--task-settings`, replacing the defaults it was started with.
+///
+/// Written as `Task:key=value;key=value`, with these keys:
+/// - `every=<secs>`
+/// - `cron=<expression>`
+/// - `jitter=<secs>`
+/// - `timeout=<secs>`, where 0 means no timeout
+/// - `backoff=linear:<start secs>:<increment secs>:<max stacks>`
+/// - `backoff=jittered:<base secs>:<max secs>`
+#[derive(Debug, Clone)]
+pub struct TaskOverride {
+    pub task: ActiveTasks,
+    pub schedule: Option<Schedule>,
+    pub jitter: Option<Duration>,
+    pub timeout: Option<Option<Duration>>,
+    pub backoff_strategy: Option<BackoffStrategy>,
+}
+
+impl TaskOverride {
+    pub fn apply(&self, settings: &mut TaskSettings) {
+        if let Some(schedule) = &self.schedule {
+            settings.schedule = schedule.clone();
+        }
+        if let Some(jitter) = self.jitter {
+            settings.jitter = jitter;
+        }
+        if let Some(timeout) = self.timeout {
+            settings.timeout = timeout;
+        }
+        if let Some(backoff_strategy) = &self.backoff_strategy {
+            settings.backoff_strategy = backoff_strategy.clone();
+        }
+    }
+}
+
+fn parse_secs(value: &str) -> anyhow::Result<Duration> {
+    let secs: u64 = value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Expected a number of seconds, got {:?}", value))?;
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_backoff(value: &str) -> anyhow::Result<BackoffStrategy> {
+    let parts: Vec<&str> = value.split(':').collect();
+    match parts.as_slice() {
+        ["linear", start, increment, max_stacks] => Ok(BackoffStrategy::Linear {
+            start: parse_secs(start)?,
+            increment: parse_secs(increment)?,
+            max_stacks: max_stacks.trim().parse()?,
+        }),
+        ["jittered", base, max] => Ok(BackoffStrategy::Jittered {
+            base: parse_secs(base)?,
+            max: parse_secs(max)?,
+        }),
+        _ => anyhow::bail!(
+            "Invalid backoff {:?}, expected linear:<start>:<increment>:<max stacks> or jittered:<base>:<max>",
+            value
+        ),
+    }
+}
+
+impl FromStr for TaskOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (task, settings) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected Task:key=value;key=value, got {:?}", s))?;
+        // Either the name used with `-d`, or the one the API shows
+        let task = task.trim();
+        let task = ActiveTasks::from_str(task, true)
+            .ok()
+            .or_else(|| serde_json::from_value(serde_json::Value::from(task)).ok())
+            .ok_or_else(|| anyhow::anyhow!("Unknown task {:?}", task))?;
+
+        let mut res = TaskOverride {
+            task,
+            schedule: None,
+            jitter: None,
+            timeout: None,
+            backoff_strategy: None,
+        };
+
+        for setting in settings.split(';').filter(|s| !s.trim().is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Expected key=value, got {:?}", setting))?;
+            match key.trim() {
+                "every" => res.schedule = Some(Schedule::Every(parse_secs(value)?)),
+                "cron" => res.schedule = Some(Schedule::cron(value)?),
+                "jitter" => res.jitter = Some(parse_secs(value)?),
+                "timeout" => {
+                    let timeout = parse_secs(value)?;
+                    res.timeout = Some((!timeout.is_zero()).then_some(timeout));
+                }
+                "backoff" => res.backoff_strategy = Some(parse_backoff(value)?),
+                other => anyhow::bail!("Unknown task setting {:?}", other),
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_task_override() {
+        let o: TaskOverride =
+            "LookForTrackedPlayerGames:cron=0,30 * * * *;jitter=30;timeout=600;backoff=jittered:5:300"
+                .parse()
+                .unwrap();
+        assert_eq!(o.task, ActiveTasks::LookForTrackedPlayerGames);
+        assert_eq!(o.jitter, Some(Duration::from_secs(30)));
+        assert_eq!(o.timeout, Some(Some(Duration::from_secs(600))));
+
+        // The next run is at most half an hour away
+        let schedule = o.schedule.unwrap();
+        assert!(matches!(schedule, Schedule::Cron(_)));
+        assert!(schedule.first_wait() <= Duration::from_secs(30 * 60));
+
+        let mut settings = TaskSettings::default();
+        let o: TaskOverride = "look-for-old-sessions:every=10;timeout=0".parse().unwrap();
+        settings.timeout = Some(Duration::from_secs(1));
+        o.apply(&mut settings);
+        assert!(matches!(settings.schedule, Schedule::Every(d) if d == Duration::from_secs(10)));
+        assert_eq!(settings.timeout, None);
+
+        assert!("NotATask:every=10".parse::<TaskOverride>().is_err());
+        assert!(
+            "LookForOldSessions:cron=not cron"
+                .parse::<TaskOverride>()
+                .is_err()
+        );
+        assert!(
+            "LookForOldSessions:every=soon"
+                .parse::<TaskOverride>()
+                .is_err()
+        );
+        assert!(
+            "LookForOldSessions:sometimes=1"
+                .parse::<TaskOverride>()
+                .is_err()
+        );
+        assert!(
+            "LookForOldSessions:backoff=linear:1"
+                .parse::<TaskOverride>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_jitter() {
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(jitter(Duration::from_secs(5)) <= Duration::from_secs(5));
+        }
+    }
+}
@@ -1,8 +1,6 @@
 #![allow(clippy::all)]
 
 use axum::response::Response;
-use serde::de::DeserializeOwned;
-use std::future::Future;
 
 /// Serves a file from the filesystem as an HTTP response
 pub async fn serve_file(file_path: &std::path::Path) -> anyhow::Result<Response> {
@@ -18,54 +16,115 @@ pub async fn serve_file(file_path: &std::path::Path) -> anyhow::Result<Response>
     Ok(response)
 }
 
-/// Extension trait for reqwest::Response to provide error handling with anyhow
-pub trait ReqwestErrorHandlingExtension
-where
-    Self: Sized + Send,
-{
-    fn anyhow_error_text(self) -> impl Future<Output = anyhow::Result<String>> + Send;
-
-    fn anyhow_error_json<T: DeserializeOwned>(
-        self,
-    ) -> impl Future<Output = anyhow::Result<T>> + Send {
-        async move {
-            let text = self.anyhow_error_text().await?;
-            Ok(serde_json::from_str(&text)?)
-        }
-    }
-}
-
-impl ReqwestErrorHandlingExtension for reqwest::Response {
-    async fn anyhow_error_text(self) -> anyhow::Result<String> {
-        let status = self.status();
-        let url = self.url().to_string();
-        let mut text = self.text().await?;
-
-        if !status.is_success() {
-            if let Ok(t) = serde_json::from_str::<serde_json::Value>(&text) {
-                text = serde_json::to_string_pretty(&t).unwrap();
-            }
-            tracing::error!(text);
-            anyhow::bail!(
-                "API Call failed {:?} with code {}: {}",
-                url,
-                status.as_u16(),
-                text
-            );
-        }
-
-        Ok(text)
-    }
-}
+#[cfg(test)]
+static TEST_GAMEDATA: include_dir::Dir =
+    include_dir::include_dir!("$CARGO_MANIFEST_DIR/examples/gamedata");
 
 /// Helper function to load game data for tests
 #[cfg(test)]
 pub fn load_game_in_test(game_id: &str) -> Option<serde_json::Value> {
     // load it from ./examples/gamedata/
-    let dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/examples/gamedata");
-    let file = dir.get_file(&format!("{}.json", game_id))?;
+    let file = TEST_GAMEDATA.get_file(&format!("{}.json", game_id))?;
     let json: serde_json::Value = serde_json::from_slice(file.contents())
         .expect("Failed to parse game JSON from examples/gamedata");
 
     Some(json)
 }
+
+/// Helper function to get the raw contents of every file in ./examples/gamedata/ for tests
+#[cfg(test)]
+pub fn all_games_in_test() -> impl Iterator<Item = (&'static str, &'static [u8])> {
+    TEST_GAMEDATA.files().map(|f| {
+        let name = f
+            .path()
+            .to_str()
+            .expect("Non UTF-8 file name in examples/gamedata");
+        (name, f.contents())
+    })
+}
+
+/// A fresh database with every migration applied, for tests that need postgres. It is dropped
+/// again when this goes out of scope.
+#[cfg(test)]
+pub struct TestDatabase {
+    pub pool: sqlx::PgPool,
+    admin_url: String,
+    name: String,
+}
+
+/// Creates a [`TestDatabase`] on the server in `DATABASE_URL`. Returns `None` when it isn't set,
+/// so tests that need a database are skipped locally. In CI (`CI` is set) a missing database
+/// fails the test instead, so these tests can't pass there without running.
+#[cfg(test)]
+pub async fn test_database() -> Option<TestDatabase> {
+    use sqlx::{ConnectOptions, Connection};
+
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+        if std::env::var_os("CI").is_some() {
+            panic!("DATABASE_URL must be set to run database tests in CI");
+        }
+        eprintln!("DATABASE_URL is not set, skipping database test");
+        return None;
+    };
+
+    let options: sqlx::postgres::PgConnectOptions = url.parse().expect("Invalid DATABASE_URL");
+    let name = format!("openfrontpro_test_{}", uuid::Uuid::new_v4().simple());
+
+    let mut admin = options
+        .clone()
+        .database("postgres")
+        .connect()
+        .await
+        .expect("Failed to connect to DATABASE_URL");
+    sqlx::query(&format!("CREATE DATABASE {}", name))
+        .execute(&mut admin)
+        .await
+        .expect("Failed to create test database");
+    admin.close().await.ok();
+
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(4)
+        .connect_with(options.database(&name))
+        .await
+        .expect("Failed to connect to test database");
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("Failed to run migrations on test database");
+
+    Some(TestDatabase {
+        pool,
+        admin_url: url,
+        name,
+    })
+}
+
+#[cfg(test)]
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        use sqlx::{ConnectOptions, Connection};
+
+        let admin_url = self.admin_url.clone();
+        let name = self.name.clone();
+        // Drop can't be async, and the test's runtime may be shutting down
+        let dropped = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let options: sqlx::postgres::PgConnectOptions = admin_url.parse()?;
+                    let mut admin = options.database("postgres").connect().await?;
+                    sqlx::query(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name))
+                        .execute(&mut admin)
+                        .await?;
+                    admin.close().await
+                })
+        })
+        .join();
+
+        if !matches!(dropped, Ok(Ok(()))) {
+            eprintln!("Failed to drop test database {}", self.name);
+        }
+    }
+}
@@ -40,7 +40,7 @@ import { performance } from "perf_hooks";
 import fs from "fs/promises";
 import { Pool } from "pg";
 import { on } from "events";
-import { Analysis, DATABASE_URL, ExtraData } from "./Types";
+import { Analysis, CLIENT_LEASE_SECS, DATABASE_URL, ExtraData, WORKER_ID } from "./Types";
 import { finalize_and_insert_analysis, load_map_data, setup } from "./Util";
 import { cleanup_previous_analysis, INSERT_DISPLAY_EVENT, INSERT_GENERAL_EVENT, INSERT_PLAYER, INSERT_PLAYER_TROOP_RATIO_CHANGE, INSERT_PLAYER_UPDATE_NEW, INSERT_SPAWN_LOCATIONS, SELECT_AND_UPDATE_JOB, UPDATE_ANALYSIS_QUEUE_STATUS, UPSERT_COMPLETED_ANALYSIS } from "./Sql";
 import { simgame } from "./SimGame";
@@ -54,7 +54,7 @@ export const base_log = new Logger();
 // Route 1: GET /retreive_game
 async function get_retreive_game(req: IncomingMessage, res: ServerResponse, pool: Pool): Promise<void> {
     try {
-        const queryResult = await pool.query(SELECT_AND_UPDATE_JOB);
+        const queryResult = await pool.query(SELECT_AND_UPDATE_JOB, [WORKER_ID, CLIENT_LEASE_SECS]);
 
         if (queryResult.rowCount === 0 || !queryResult || !queryResult.rows[0]) {
             console.log("No pending games found.");
@@ -147,6 +147,7 @@ async function post_submit_game(req: IncomingMessage, res: ServerResponse, pool:
     await pool.query(UPDATE_ANALYSIS_QUEUE_STATUS, [
         analysis.game_id,
         new_state,
+        WORKER_ID,
     ]);
     clearInterval(interval);
 
@@ -36,9 +36,9 @@ import { performance } from "perf_hooks";
 import fs from "fs/promises";
 import { Pool } from "pg";
 import { on } from "events";
-import { Analysis, DATABASE_URL, ExtraData } from "./Types";
+import { Analysis, DATABASE_URL, ExtraData, WORKER_ID, WORKER_LEASE_SECS } from "./Types";
 import { finalize_and_insert_analysis, load_map_data, setup } from "./Util";
-import { cleanup_previous_analysis, INSERT_DISPLAY_EVENT, INSERT_GENERAL_EVENT, INSERT_PLAYER, INSERT_PLAYER_TROOP_RATIO_CHANGE, INSERT_PLAYER_UPDATE_NEW, INSERT_SPAWN_LOCATIONS, SELECT_AND_UPDATE_JOB, UPDATE_ANALYSIS_QUEUE_STATUS, UPSERT_COMPLETED_ANALYSIS } from "./Sql";
+import { cleanup_previous_analysis, INSERT_DISPLAY_EVENT, INSERT_GENERAL_EVENT, INSERT_PLAYER, INSERT_PLAYER_TROOP_RATIO_CHANGE, INSERT_PLAYER_UPDATE_NEW, INSERT_SPAWN_LOCATIONS, RENEW_JOB_LEASE, SELECT_AND_UPDATE_JOB, UPDATE_ANALYSIS_QUEUE_STATUS, UPSERT_COMPLETED_ANALYSIS } from "./Sql";
 import { simgame } from "./SimGame";
 import { db_interaction_server } from "./DBInteractionServer";
 import { db_sim_client, db_sim_single_game } from "./DBInteractionClient";
@@ -53,11 +53,17 @@ export let global_pool: Pool | null = null;
 async function process_pending_games(pool: Pool): Promise<void> {
     // Select 1 job from DB by updating a single row from the analysis_queue table (INNER JOIN with finished_games)
     // We set the analysis_status to 'Running' and then select the game_id and result_json
-    const res = await pool.query(SELECT_AND_UPDATE_JOB);
+    const res = await pool.query(SELECT_AND_UPDATE_JOB, [WORKER_ID, WORKER_LEASE_SECS]);
 
     for (const game of res.rows) {
         console.log("Game ID: ", game.game_id);
 
+        // Keep renewing the lease while we simulate, or the job is given to another worker
+        const heartbeat = setInterval(() => {
+            pool.query(RENEW_JOB_LEASE, [game.game_id, WORKER_ID, WORKER_LEASE_SECS])
+                .catch((e) => console.error("Failed to renew lease: ", e));
+        }, (WORKER_LEASE_SECS * 1000) / 3);
+
         let new_state = "Completed";
         const time_now = Date.now();
         try {
@@ -71,6 +77,7 @@ async function process_pending_games(pool: Pool): Promise<void> {
             console.log("The analysis failed for game", game.game_id, e);
             new_state = "Failed";
         } finally {
+            clearInterval(heartbeat);
             const time_taken = Date.now() - time_now;
 
             console.log(
@@ -82,6 +89,7 @@ async function process_pending_games(pool: Pool): Promise<void> {
         await pool.query(UPDATE_ANALYSIS_QUEUE_STATUS, [
             game.game_id,
             new_state,
+            WORKER_ID,
         ]);
     }
 }
@@ -139,6 +147,7 @@ function endall() {
         global_pool?.query(UPDATE_ANALYSIS_QUEUE_STATUS, [
             current_processing_game,
             "Pending",
+            WORKER_ID,
         ]).catch((e) => console.error("Failed to reset game state: ", e));
     } else {
         console.log("No current processing game.");
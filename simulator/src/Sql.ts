@@ -54,34 +54,57 @@ export const INSERT_GENERAL_EVENT_PACKED = format_sql`
   VALUES %L
 `;
 
+// Claims the next job the same way `POST /api/v1/worker/claim` does: in the order of
+// analysis_queue_order, leased to worker $1 for $2 seconds
 export const SELECT_AND_UPDATE_JOB = format_sql`
   WITH my_job AS (
     SELECT
-      fg.game_id, fg.result_json
+      aq.game_id
     FROM
-      finished_games fg
-    INNER JOIN analysis_queue aq ON aq.game_id = fg.game_id
+      analysis_queue aq
+    INNER JOIN analysis_queue_order o ON o.game_id = aq.game_id
+    INNER JOIN finished_games fg ON fg.game_id = aq.game_id
     WHERE
       aq.status = 'Pending'
+      AND fg.is_ok
     ORDER BY
-      fg.inserted_at_unix_sec ASC
+      o.position ASC
     LIMIT 1
+    FOR UPDATE OF aq SKIP LOCKED
   )
   UPDATE analysis_queue aq
   SET
     status = 'Running',
-    started_unix_sec = EXTRACT(EPOCH FROM NOW())
-  FROM my_job
-  WHERE aq.game_id = my_job.game_id AND aq.status = 'Pending'
-  RETURNING my_job.game_id, my_job.result_json
+    started_unix_sec = EXTRACT(EPOCH FROM NOW()),
+    worker_id = $1,
+    lease_expires_unix_sec = EXTRACT(EPOCH FROM NOW()) + $2::BIGINT
+  FROM my_job, finished_games fg
+  WHERE
+    aq.game_id = my_job.game_id
+    AND fg.game_id = my_job.game_id
+    AND aq.status = 'Pending'
+  RETURNING aq.game_id, fg.result_json
+`;
+
+export const RENEW_JOB_LEASE = format_sql`
+  UPDATE analysis_queue
+  SET
+    lease_expires_unix_sec = EXTRACT(EPOCH FROM NOW()) + $3::BIGINT
+  WHERE
+    game_id = $1
+    AND worker_id = $2
+    AND status = 'Running'
 `;
 
+// Does nothing if our lease expired and the job was given to someone else
 export const UPDATE_ANALYSIS_QUEUE_STATUS = format_sql`
   UPDATE analysis_queue
   SET
-    status = $2
+    status = $2,
+    lease_expires_unix_sec = NULL
   WHERE
     game_id = $1
+    AND worker_id = $3
     AND status in ('Pending', 'Running')
 `;
 
@@ -36,9 +36,10 @@ import { performance } from "perf_hooks";
 import fs from "fs/promises";
 import { Pool } from "pg";
 import { on } from "events";
+import { hostname } from "os";
 
 // ===== Constants / Types =====
-export let { DATABASE_URL, MAP_FOLDER, FINALIZE_METHOD } = process.env;
+export let { DATABASE_URL, MAP_FOLDER, FINALIZE_METHOD, WORKER_ID } = process.env;
 if (!DATABASE_URL) {
     console.log("Missing DATABASE_URL environment variable");
 }
@@ -51,6 +52,17 @@ if (!FINALIZE_METHOD) {
     FINALIZE_METHOD = "db";
 }
 
+// Shown as the worker on the jobs we claim
+if (!WORKER_ID) {
+    WORKER_ID = `sim-${hostname()}`;
+}
+
+// How long we hold a job without renewing it, like worker_lease_secs on the server
+export const WORKER_LEASE_SECS = Number(process.env.WORKER_LEASE_SECS || 120);
+
+// Games handed to a remote client can't be renewed, so they get one long lease instead
+export const CLIENT_LEASE_SECS = Number(process.env.CLIENT_LEASE_SECS || 60 * 60);
+
 export type MapData = {
     minimap: Uint8Array;
     map: Uint8Array;